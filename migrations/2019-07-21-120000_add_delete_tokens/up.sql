@@ -0,0 +1,2 @@
+-- images uploaded before this migration can't be deleted
+ALTER TABLE images ADD COLUMN delete_token varchar(64) NOT NULL DEFAULT '';
@@ -0,0 +1,5 @@
+-- public ids are random, so images can't be enumerated by incrementing image_id
+ALTER TABLE images ADD COLUMN public_id varchar(32);
+UPDATE images SET public_id = substr(md5(random()::text || image_id::text), 1, 10);
+ALTER TABLE images ALTER COLUMN public_id SET NOT NULL;
+ALTER TABLE images ADD CONSTRAINT images_public_id_key UNIQUE (public_id);
@@ -0,0 +1,2 @@
+DELETE FROM images WHERE data IS NULL;
+ALTER TABLE images ALTER COLUMN data SET NOT NULL;
@@ -0,0 +1,2 @@
+-- data is NULL for images stored outside of database (e.g. in S3 bucket)
+ALTER TABLE images ALTER COLUMN data DROP NOT NULL;
@@ -0,0 +1,9 @@
+ALTER TABLE images
+    DROP COLUMN width,
+    DROP COLUMN height,
+    DROP COLUMN size,
+    DROP COLUMN color_type,
+    DROP COLUMN hash,
+    DROP COLUMN created_at,
+    DROP COLUMN source,
+    DROP COLUMN source_url;
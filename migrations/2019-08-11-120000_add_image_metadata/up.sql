@@ -0,0 +1,22 @@
+ALTER TABLE images
+    ADD COLUMN width integer NOT NULL DEFAULT 0,
+    ADD COLUMN height integer NOT NULL DEFAULT 0,
+    ADD COLUMN size bigint NOT NULL DEFAULT 0,
+    ADD COLUMN color_type varchar(16) NOT NULL DEFAULT '',
+    ADD COLUMN hash varchar(64) NOT NULL DEFAULT '',
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN source varchar(16) NOT NULL DEFAULT 'unknown',
+    ADD COLUMN source_url text;
+
+-- dimensions of existing images can't be computed here, so they are left as 0
+UPDATE images
+    SET size = octet_length(data), hash = encode(sha256(data), 'hex')
+    WHERE data IS NOT NULL;
+
+ALTER TABLE images
+    ALTER COLUMN width DROP DEFAULT,
+    ALTER COLUMN height DROP DEFAULT,
+    ALTER COLUMN size DROP DEFAULT,
+    ALTER COLUMN color_type DROP DEFAULT,
+    ALTER COLUMN hash DROP DEFAULT,
+    ALTER COLUMN source DROP DEFAULT;
@@ -0,0 +1,4 @@
+ALTER TABLE images ADD COLUMN data bytea;
+UPDATE images SET data = blobs.data FROM blobs WHERE images.blob_id = blobs.blob_id;
+ALTER TABLE images DROP COLUMN blob_id;
+DROP TABLE blobs;
@@ -0,0 +1,22 @@
+-- identical uploads share one blob, which is removed when its last image is deleted
+CREATE TABLE blobs (
+  blob_id serial PRIMARY KEY,
+  hash varchar(64) NOT NULL,
+  -- name of object for data stored outside of database (e.g. in S3 bucket)
+  object_key varchar(64) NOT NULL,
+  data bytea,
+  refcount integer NOT NULL
+);
+
+-- existing images get their own blobs, so data stored outside of database doesn't have to be moved
+INSERT INTO blobs (blob_id, hash, object_key, data, refcount)
+    SELECT image_id, hash, public_id, data, 1 FROM images;
+SELECT setval('blobs_blob_id_seq', (SELECT coalesce(max(blob_id), 0) + 1 FROM blobs), false);
+
+ALTER TABLE images ADD COLUMN blob_id integer REFERENCES blobs (blob_id);
+UPDATE images SET blob_id = image_id;
+ALTER TABLE images ALTER COLUMN blob_id SET NOT NULL;
+ALTER TABLE images DROP COLUMN data;
+
+CREATE INDEX blobs_hash_idx ON blobs (hash);
+CREATE INDEX images_blob_id_idx ON images (blob_id);
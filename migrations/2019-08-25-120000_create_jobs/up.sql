@@ -0,0 +1,20 @@
+-- remote imports which are processed in background
+CREATE TABLE jobs (
+  job_id serial PRIMARY KEY,
+  public_id varchar(32) NOT NULL UNIQUE,
+  created_at timestamptz NOT NULL
+);
+
+-- each location is downloaded (and its state is updated) independently
+CREATE TABLE job_items (
+  job_id integer NOT NULL REFERENCES jobs (job_id) ON DELETE CASCADE,
+  position integer NOT NULL,
+  url text NOT NULL,
+  status varchar(16) NOT NULL,
+  -- public id and delete token of uploaded image
+  image_id varchar(32),
+  delete_token varchar(32),
+  error_code varchar(32),
+  error_message text,
+  PRIMARY KEY (job_id, position)
+);
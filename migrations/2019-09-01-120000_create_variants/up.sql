@@ -0,0 +1,10 @@
+-- named previews, which are generated on upload, belong to blob, so identical images share them
+CREATE TABLE variants (
+  blob_id integer NOT NULL REFERENCES blobs (blob_id) ON DELETE CASCADE,
+  name varchar(32) NOT NULL,
+  format integer NOT NULL,
+  -- name of object for data stored outside of database (e.g. in S3 bucket)
+  object_key varchar(128) NOT NULL,
+  data bytea,
+  PRIMARY KEY (blob_id, name)
+);
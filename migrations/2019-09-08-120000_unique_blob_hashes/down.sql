@@ -0,0 +1,2 @@
+DROP INDEX blobs_hash_key;
+CREATE INDEX blobs_hash_idx ON blobs (hash);
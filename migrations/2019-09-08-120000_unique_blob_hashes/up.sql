@@ -0,0 +1,15 @@
+-- concurrent uploads could create several blobs with the same hash,
+-- their images are moved to the oldest one (objects of removed blobs are left in bucket)
+UPDATE blobs SET refcount = duplicates.refcount
+    FROM (SELECT min(blob_id) AS blob_id, sum(refcount)::integer AS refcount
+          FROM blobs WHERE hash <> '' GROUP BY hash HAVING count(*) > 1) duplicates
+    WHERE blobs.blob_id = duplicates.blob_id;
+UPDATE images SET blob_id = oldest.blob_id
+    FROM blobs, (SELECT hash, min(blob_id) AS blob_id FROM blobs WHERE hash <> '' GROUP BY hash) oldest
+    WHERE images.blob_id = blobs.blob_id AND blobs.hash = oldest.hash AND images.blob_id <> oldest.blob_id;
+DELETE FROM blobs
+    WHERE hash <> '' AND blob_id NOT IN (SELECT min(blob_id) FROM blobs WHERE hash <> '' GROUP BY hash);
+
+-- hash of images stored in S3 before metadata was recorded is empty, they are not deduplicated
+DROP INDEX blobs_hash_idx;
+CREATE UNIQUE INDEX blobs_hash_key ON blobs (hash) WHERE hash <> '';
@@ -0,0 +1,155 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use failure::Error;
+use log::{info, warn};
+
+use crate::models::{Image, ImageId, ImageFormat};
+use super::{Cache, CacheKey};
+use super::lru::Lru;
+
+const TMP_EXTENSION: &str = "tmp";
+
+/// Images kept in `{root}/{id}/{params}`, format of image is the extension of its params.
+/// Index is rebuilt from files when cache is opened, so cached images survive restart.
+/// Failures are only logged, missing image is just a cache miss.
+pub struct DiskCache {
+    root: PathBuf,
+    lru: Mutex<Lru<ImageFormat>>
+}
+
+/// format of cached image, None for temporary and unknown files
+fn file_format(params: &str) -> Option<ImageFormat> {
+    let extension = params.rsplit('.').next()?;
+    if extension == TMP_EXTENSION {
+        return None;
+    }
+    extension.parse().ok()
+}
+
+/// cached files ordered by time of modification
+fn scan(root: &Path) -> Result<Vec<(SystemTime, CacheKey, ImageFormat, u64)>, Error> {
+    let mut files = Vec::new();
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        let id = match dir.file_name().to_str().map(str::parse::<ImageId>) {
+            Some(Ok(id)) => id,
+            _ => continue
+        };
+
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let params = file.file_name().to_string_lossy().into_owned();
+            let metadata = file.metadata()?;
+            match file_format(&params) {
+                Some(format) if metadata.is_file() => {
+                    let key = CacheKey { id: id.clone(), params };
+                    files.push((metadata.modified()?, key, format, metadata.len()));
+                },
+                // most likely leftover of interrupted write
+                _ => remove_file(&file.path())
+            }
+        }
+    }
+
+    files.sort_by_key(|(modified, ..)| *modified);
+    Ok(files)
+}
+
+fn remove_file(path: &Path) {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => warn!("Failed to remove cached file {:?}: {}", path, e),
+        Ok(_) => {}
+    }
+}
+
+impl DiskCache {
+    pub fn open<P: AsRef<Path>>(root: P, capacity: u64) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let mut lru = Lru::new(capacity);
+        for (_, key, format, size) in scan(&root)? {
+            // capacity could be decreased since last run
+            for (key, _) in lru.insert(key.clone(), format, size) {
+                remove_file(&file_path(&root, &key));
+            }
+        }
+
+        let (count, size) = lru.usage();
+        info!("Opened disk cache at {:?} with {} images (total size is {})", root, count, size);
+        Ok(DiskCache {
+            root,
+            lru: Mutex::new(lru)
+        })
+    }
+
+    fn write(&self, key: &CacheKey, image: &Image) -> Result<(), Error> {
+        let path = file_path(&self.root, key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // file is renamed when it is complete, so partially written images are never read
+        let tmp = path.with_file_name(format!("{}.{}", ImageId::generate(), TMP_EXTENSION));
+        let mut file = File::create(&tmp)?;
+        file.write_all(image.data())?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn file_path(root: &Path, key: &CacheKey) -> PathBuf {
+    root.join(key.id.as_str()).join(&key.params)
+}
+
+impl Cache for DiskCache {
+    fn get(&self, key: &CacheKey) -> Option<Image> {
+        // lock isn't held while file is read
+        let format = *self.lru.lock().unwrap().get(key)?;
+        match fs::read(file_path(&self.root, key)) {
+            Ok(data) => Some(Image::from_raw(data, format)),
+            Err(e) => {
+                warn!("Failed to read cached image {:?}: {}", key, e);
+                self.lru.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &CacheKey, image: &Image) {
+        let size = image.data().len() as u64;
+        if !self.lru.lock().unwrap().fits(size) {
+            return;
+        }
+
+        if let Err(e) = self.write(key, image) {
+            warn!("Failed to cache image {:?}: {}", key, e);
+            return;
+        }
+        let evicted = self.lru.lock().unwrap().insert(key.clone(), image.format(), size);
+        for (key, _) in evicted {
+            remove_file(&file_path(&self.root, &key));
+        }
+    }
+
+    fn invalidate(&self, id: &ImageId) {
+        self.lru.lock().unwrap().remove_image(id);
+        match fs::remove_dir_all(self.root.join(id.as_str())) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => warn!("Failed to remove cached images of {}: {}", id, e),
+            Ok(_) => {}
+        }
+    }
+
+    fn usage(&self) -> (usize, u64) {
+        self.lru.lock().unwrap().usage()
+    }
+}
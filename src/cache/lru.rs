@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::models::ImageId;
+use super::CacheKey;
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    /// time of last use, key of entry in order
+    tick: u64
+}
+
+/// Entries ordered by time of their last use, least recently used entries
+/// are evicted when total size exceeds capacity
+pub struct Lru<V> {
+    entries: HashMap<CacheKey, Entry<V>>,
+    order: BTreeMap<u64, CacheKey>,
+    capacity: u64,
+    size: u64,
+    tick: u64
+}
+
+impl<V> Lru<V> {
+    pub fn new(capacity: u64) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            capacity,
+            size: 0,
+            tick: 0
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// entries larger than capacity are never inserted
+    pub fn fits(&self, size: u64) -> bool {
+        size <= self.capacity
+    }
+
+    /// get entry and mark it as recently used
+    pub fn get(&mut self, key: &CacheKey) -> Option<&V> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.clone());
+        entry.tick = tick;
+        Some(&entry.value)
+    }
+
+    /// Insert or replace entry, entries which were evicted to free space are returned.
+    /// Replaced entry is not returned, it has the same key.
+    pub fn insert(&mut self, key: CacheKey, value: V, size: u64) -> Vec<(CacheKey, V)> {
+        self.remove(&key);
+        if !self.fits(size) {
+            return Vec::new();
+        }
+
+        let tick = self.next_tick();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, Entry { value, size, tick });
+        self.size += size;
+
+        let mut evicted = Vec::new();
+        while self.size > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => tick,
+                None => break
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.size -= entry.size;
+                    evicted.push((key, entry.value));
+                }
+            }
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+
+    /// remove all entries of image
+    pub fn remove_image(&mut self, id: &ImageId) -> Vec<(CacheKey, V)> {
+        let keys: Vec<CacheKey> = self.entries.keys()
+            .filter(|key| &key.id == id)
+            .cloned()
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| self.remove(&key).map(|value| (key, value)))
+            .collect()
+    }
+
+    /// number of entries and their total size
+    pub fn usage(&self) -> (usize, u64) {
+        (self.entries.len(), self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+    use crate::cache::CacheKey;
+    use crate::models::ImageId;
+
+    fn key(id: &str, params: &str) -> CacheKey {
+        CacheKey { id: ImageId(id.to_string()), params: params.to_string() }
+    }
+
+    #[test]
+    fn eviction() {
+        let mut lru = Lru::new(10);
+        assert!(lru.insert(key("a", "1"), 1, 4).is_empty());
+        assert!(lru.insert(key("a", "2"), 2, 4).is_empty());
+        assert!(lru.insert(key("b", "1"), 3, 11).is_empty());
+        assert_eq!(lru.usage(), (2, 8));
+
+        // recently used entry is kept
+        assert_eq!(lru.get(&key("a", "1")), Some(&1));
+        assert_eq!(lru.insert(key("b", "2"), 4, 4), vec![(key("a", "2"), 2)]);
+        assert_eq!(lru.get(&key("a", "2")), None);
+
+        // replaced entry is not evicted
+        assert!(lru.insert(key("b", "2"), 5, 6).is_empty());
+        assert_eq!(lru.usage(), (2, 10));
+
+        assert_eq!(lru.remove_image(&ImageId("a".to_string())), vec![(key("a", "1"), 1)]);
+        assert_eq!(lru.usage(), (1, 6));
+    }
+}
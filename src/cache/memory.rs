@@ -0,0 +1,37 @@
+use std::sync::Mutex;
+
+use crate::models::{Image, ImageId};
+use super::{Cache, CacheKey};
+use super::lru::Lru;
+
+/// Images kept in memory, size of cache is total size of their data
+pub struct MemoryCache {
+    lru: Mutex<Lru<Image>>
+}
+
+impl MemoryCache {
+    pub fn new(capacity: u64) -> Self {
+        MemoryCache {
+            lru: Mutex::new(Lru::new(capacity))
+        }
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &CacheKey) -> Option<Image> {
+        self.lru.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &CacheKey, image: &Image) {
+        let size = image.data().len() as u64;
+        self.lru.lock().unwrap().insert(key.clone(), image.clone(), size);
+    }
+
+    fn invalidate(&self, id: &ImageId) {
+        self.lru.lock().unwrap().remove_image(id);
+    }
+
+    fn usage(&self) -> (usize, u64) {
+        self.lru.lock().unwrap().usage()
+    }
+}
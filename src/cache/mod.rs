@@ -0,0 +1,176 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use failure::Error;
+use serde::{Serialize, Deserialize};
+
+use crate::config::CacheConfig;
+use crate::models::{Image, ImageId, ImageFormat, ResizeMode};
+
+mod lru;
+mod memory;
+mod disk;
+
+pub use self::memory::MemoryCache;
+pub use self::disk::DiskCache;
+
+/// Derived image (e.g. preview) identified by image and its transformation
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct CacheKey {
+    pub id: ImageId,
+    /// parameters of transformation, ending with extension of output format
+    pub params: String
+}
+
+impl CacheKey {
+    pub fn preview(id: &ImageId, (width, height): (u32, u32), mode: ResizeMode, format: ImageFormat) -> CacheKey {
+        CacheKey {
+            id: id.clone(),
+            params: format!("preview-{}x{}-{}.{}", width, height, mode, format)
+        }
+    }
+}
+
+/// Tier of cache, it only keeps images which fit into its capacity
+pub trait Cache: 'static + Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<Image>;
+    fn put(&self, key: &CacheKey, image: &Image);
+    /// remove all derived images of image
+    fn invalidate(&self, id: &ImageId);
+    /// number of cached images and their total size
+    fn usage(&self) -> (usize, u64);
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TierStats {
+    pub entries: usize,
+    pub size: u64
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// usage of each tier, starting with the fastest one
+    pub tiers: Vec<TierStats>
+}
+
+/// Cache of derived images, which consists of tiers (e.g. memory and disk).
+/// Images found in slower tier are copied to faster ones.
+pub struct ImageCache {
+    tiers: Vec<Box<dyn Cache>>,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl ImageCache {
+    pub fn new(tiers: Vec<Box<dyn Cache>>) -> Self {
+        ImageCache {
+            tiers,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    pub fn from_config(config: &CacheConfig) -> Result<Self, Error> {
+        let mut tiers: Vec<Box<dyn Cache>> = Vec::new();
+        if config.memory_size > 0 {
+            tiers.push(Box::new(MemoryCache::new(config.memory_size)));
+        }
+        if let Some(path) = &config.disk_path {
+            tiers.push(Box::new(DiskCache::open(path, config.disk_size)?));
+        }
+        Ok(ImageCache::new(tiers))
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Image> {
+        for (i, tier) in self.tiers.iter().enumerate() {
+            if let Some(image) = tier.get(key) {
+                for faster in &self.tiers[..i] {
+                    faster.put(key, &image);
+                }
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(image);
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn put(&self, key: &CacheKey, image: &Image) {
+        for tier in &self.tiers {
+            tier.put(key, image);
+        }
+    }
+
+    /// should be called when image is deleted
+    pub fn invalidate(&self, id: &ImageId) {
+        for tier in &self.tiers {
+            tier.invalidate(id);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            tiers: self.tiers.iter()
+                .map(|tier| tier.usage())
+                .map(|(entries, size)| TierStats { entries, size })
+                .collect()
+        }
+    }
+}
+
+/// memory cache with default size
+impl Default for ImageCache {
+    fn default() -> Self {
+        ImageCache::from_config(&CacheConfig::default())
+            .expect("Failed to create memory cache")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageCache, CacheKey, Cache, MemoryCache, DiskCache};
+    use crate::models::{Image, ImageId, ImageFormat, ResizeMode};
+
+    const TEST_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/servo.png"));
+
+    #[test]
+    fn tiers() {
+        let root = std::env::temp_dir().join(format!("image-upload-cache-{}", ImageId::generate()));
+        let image = Image::from_raw(TEST_IMAGE.to_vec(), ImageFormat::PNG);
+        let id = ImageId::generate();
+        let key = CacheKey::preview(&id, (100, 50), ResizeMode::Fit, ImageFormat::PNG);
+        assert_eq!(key.params, "preview-100x50-fit.png");
+
+        let cache = ImageCache::new(vec![
+            Box::new(MemoryCache::new(TEST_IMAGE.len() as u64)),
+            Box::new(DiskCache::open(&root, 10 * TEST_IMAGE.len() as u64).unwrap())
+        ]);
+        assert!(cache.get(&key).is_none());
+        cache.put(&key, &image);
+        assert_eq!(cache.get(&key).unwrap().data(), TEST_IMAGE);
+
+        // entry evicted from memory is still on disk
+        let other = CacheKey::preview(&id, (10, 10), ResizeMode::Fill, ImageFormat::PNG);
+        cache.put(&other, &image);
+        assert_eq!(cache.get(&key).unwrap().data(), TEST_IMAGE);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.tiers[0].entries, 1);
+        assert_eq!(stats.tiers[1].entries, 2);
+
+        // disk tier survives restart
+        let disk = DiskCache::open(&root, 10 * TEST_IMAGE.len() as u64).unwrap();
+        assert_eq!(disk.usage(), (2, 2 * TEST_IMAGE.len() as u64));
+        assert_eq!(disk.get(&other).unwrap().format(), ImageFormat::PNG);
+
+        cache.invalidate(&id);
+        assert!(cache.get(&key).is_none());
+        assert!(!root.join(id.as_str()).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
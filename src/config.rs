@@ -0,0 +1,378 @@
+use std::env;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use failure::{Error, format_err};
+
+use crate::models::Variant;
+
+/// variants which are generated on upload unless UPLOAD_VARIANTS is set
+const DEFAULT_VARIANTS: &[&str] = &["thumb=100x100:cover", "small=320x320", "medium=800x800"];
+
+/// parse size in "{width}x{height}" or "{size}" (square) form
+pub fn parse_size(s: &str) -> Result<(u32, u32), Error> {
+    let parse = |v: &str| v.trim().parse::<u32>()
+        .map_err(|e| format_err!("Invalid size {}: {}", s, e));
+
+    match s.find('x') {
+        Some(pos) => Ok((parse(&s[..pos])?, parse(&s[pos + 1..])?)),
+        None => parse(s).map(|size| (size, size))
+    }
+}
+
+fn var<T>(name: &str, default: T) -> Result<T, Error>
+    where T: FromStr,
+          T::Err: Display
+{
+    match env::var(name) {
+        Ok(value) => value.parse()
+            .map_err(|e| format_err!("Invalid value of {}: {}", name, e)),
+        Err(_) => Ok(default)
+    }
+}
+
+fn size_var(name: &str, default: (u32, u32)) -> Result<(u32, u32), Error> {
+    match env::var(name) {
+        Ok(value) => parse_size(&value),
+        Err(_) => Ok(default)
+    }
+}
+
+fn sizes_var(name: &str) -> Result<Vec<(u32, u32)>, Error> {
+    match env::var(name) {
+        Ok(value) => value.split(',')
+            .filter(|size| !size.trim().is_empty())
+            .map(parse_size)
+            .collect(),
+        Err(_) => Ok(Vec::new())
+    }
+}
+
+/// comma separated list
+fn list_var<T>(name: &str, default: &[&str]) -> Result<Vec<T>, Error>
+    where T: FromStr,
+          T::Err: Display
+{
+    let value = env::var(name).unwrap_or_else(|_| default.join(","));
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|e| format_err!("Invalid value of {}: {}", name, e)))
+        .collect()
+}
+
+/// network in CIDR notation (e.g. "10.0.0.0/8"), single address is a network too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u32
+}
+
+/// address as integer and its width in bits
+fn address_bits(address: IpAddr) -> (u128, u32) {
+    match address {
+        IpAddr::V4(address) => (u128::from(u32::from(address)), 32),
+        IpAddr::V6(address) => (u128::from(address), 128)
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, width) = address_bits(self.address);
+        let (address, address_width) = address_bits(address);
+        if width != address_width {
+            return false;
+        }
+
+        // shifting by 128 bits overflows, but /0 contains everything anyway
+        let shift = width - self.prefix;
+        shift == 128 || network >> shift == address >> shift
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None)
+        };
+
+        let address = address.parse::<IpAddr>()
+            .map_err(|e| format_err!("Invalid network {}: {}", s, e))?;
+        let width = address_bits(address).1;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>()
+                .map_err(|e| format_err!("Invalid network {}: {}", s, e))?,
+            None => width
+        };
+
+        if prefix > width {
+            return Err(format_err!("Invalid network {}: prefix is longer than address", s));
+        }
+        Ok(IpNetwork { address, prefix })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PreviewConfig {
+    /// size of preview when it is not specified in request
+    pub default_size: (u32, u32),
+    /// minimal allowed width and height
+    pub min_size: u32,
+    /// maximal allowed width and height
+    pub max_size: u32,
+    /// if not empty, only these sizes can be requested
+    pub allowed_sizes: Vec<(u32, u32)>
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        PreviewConfig {
+            default_size: (100, 100),
+            min_size: 16,
+            max_size: 1024,
+            allowed_sizes: Vec::new()
+        }
+    }
+}
+
+impl PreviewConfig {
+    fn from_env() -> Result<Self, Error> {
+        let default = PreviewConfig::default();
+
+        Ok(PreviewConfig {
+            default_size: size_var("PREVIEW_DEFAULT_SIZE", default.default_size)?,
+            min_size: var("PREVIEW_MIN_SIZE", default.min_size)?,
+            max_size: var("PREVIEW_MAX_SIZE", default.max_size)?,
+            allowed_sizes: sizes_var("PREVIEW_SIZES")?
+        })
+    }
+}
+
+/// Limits for uploaded images, which prevent exhausting memory with huge requests
+/// or images which are small when compressed (decompression bombs)
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// maximal size of single image in bytes
+    pub max_file_size: usize,
+    /// maximal size of request in bytes (JSON body or all images of multipart request)
+    pub max_request_size: usize,
+    /// maximal number of images in single request
+    pub max_files: usize,
+    /// maximal width and height of image
+    pub max_dimension: u32,
+    /// maximal number of pixels (width * height) of image
+    pub max_pixels: u64,
+    /// named previews which are generated on upload and stored with image
+    pub variants: Vec<Variant>
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            max_file_size: 10 * 1024 * 1024,
+            max_request_size: 32 * 1024 * 1024,
+            max_files: 16,
+            max_dimension: 16384,
+            max_pixels: 50_000_000,
+            variants: DEFAULT_VARIANTS.iter()
+                .map(|variant| variant.parse().expect("Invalid default variant"))
+                .collect()
+        }
+    }
+}
+
+impl UploadConfig {
+    fn from_env() -> Result<Self, Error> {
+        let default = UploadConfig::default();
+
+        Ok(UploadConfig {
+            max_file_size: var("UPLOAD_MAX_FILE_SIZE", default.max_file_size)?,
+            max_request_size: var("UPLOAD_MAX_REQUEST_SIZE", default.max_request_size)?,
+            max_files: var("UPLOAD_MAX_FILES", default.max_files)?,
+            max_dimension: var("UPLOAD_MAX_DIMENSION", default.max_dimension)?,
+            max_pixels: var("UPLOAD_MAX_PIXELS", default.max_pixels)?,
+            variants: list_var("UPLOAD_VARIANTS", DEFAULT_VARIANTS)?
+        })
+    }
+}
+
+/// Restrictions for remote uploads, which prevent using service to reach internal hosts
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    /// allowed schemes of remote URLs
+    pub schemes: Vec<String>,
+    /// if not empty, only these hosts (and their subdomains) can be used
+    pub allowed_hosts: Vec<String>,
+    /// hosts (and their subdomains) which can't be used
+    pub denied_hosts: Vec<String>,
+    /// networks which can't be used in addition to private, loopback, link-local and other special ones
+    pub denied_networks: Vec<IpNetwork>,
+    /// allow special addresses (e.g. for testing with local server)
+    pub allow_private: bool,
+    /// maximal time to establish connection
+    pub connect_timeout: Duration,
+    /// maximal time to wait for response and to receive its body
+    pub read_timeout: Duration,
+    pub max_redirects: usize,
+    /// number of images which are downloaded in parallel for single request
+    pub concurrency: usize,
+    /// number of images which are downloaded in parallel for all async jobs
+    pub job_concurrency: usize,
+    pub user_agent: String
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            denied_networks: Vec::new(),
+            allow_private: false,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            concurrency: 4,
+            job_concurrency: 8,
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        }
+    }
+}
+
+impl RemoteConfig {
+    fn from_env() -> Result<Self, Error> {
+        let default = RemoteConfig::default();
+        let lowercase = |items: Vec<String>| items.into_iter().map(|item| item.to_ascii_lowercase()).collect();
+        let seconds_var = |name: &str, default: Duration| var(name, default.as_secs()).map(Duration::from_secs);
+
+        Ok(RemoteConfig {
+            schemes: lowercase(list_var("REMOTE_SCHEMES", &["http", "https"])?),
+            allowed_hosts: lowercase(list_var("REMOTE_ALLOWED_HOSTS", &[])?),
+            denied_hosts: lowercase(list_var("REMOTE_DENIED_HOSTS", &[])?),
+            denied_networks: list_var("REMOTE_DENIED_NETWORKS", &[])?,
+            allow_private: var("REMOTE_ALLOW_PRIVATE", false)?,
+            connect_timeout: seconds_var("REMOTE_CONNECT_TIMEOUT", default.connect_timeout)?,
+            read_timeout: seconds_var("REMOTE_READ_TIMEOUT", default.read_timeout)?,
+            max_redirects: var("REMOTE_MAX_REDIRECTS", default.max_redirects)?,
+            concurrency: var("REMOTE_CONCURRENCY", default.concurrency)?,
+            job_concurrency: var("REMOTE_JOB_CONCURRENCY", default.job_concurrency)?,
+            user_agent: var("REMOTE_USER_AGENT", default.user_agent)?
+        })
+    }
+}
+
+/// Cache of derived images (e.g. previews of arbitrary size)
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// total size of images cached in memory in bytes, 0 disables memory cache
+    pub memory_size: u64,
+    /// directory of on-disk cache, it is used for images evicted from memory
+    pub disk_path: Option<PathBuf>,
+    /// total size of images cached on disk in bytes
+    pub disk_size: u64
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            memory_size: 64 * 1024 * 1024,
+            disk_path: None,
+            disk_size: 1024 * 1024 * 1024
+        }
+    }
+}
+
+impl CacheConfig {
+    fn from_env() -> Result<Self, Error> {
+        let default = CacheConfig::default();
+
+        Ok(CacheConfig {
+            memory_size: var("CACHE_MEMORY_SIZE", default.memory_size)?,
+            disk_path: env::var("CACHE_DISK_PATH").ok().map(PathBuf::from),
+            disk_size: var("CACHE_DISK_SIZE", default.disk_size)?
+        })
+    }
+}
+
+/// Storage backend, selected with STORAGE environment variable
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Postgres {
+        url: String
+    },
+    Filesystem {
+        path: PathBuf
+    },
+    /// image data is kept in S3 compatible bucket, metadata in postgres
+    S3 {
+        url: String,
+        bucket: String,
+        prefix: String,
+        region: String,
+        /// custom endpoint for S3 compatible services
+        endpoint: Option<String>,
+        /// access and secret keys, default credentials are used if not set
+        credentials: Option<(String, String)>
+    }
+}
+
+impl StorageConfig {
+    fn from_env() -> Result<Self, Error> {
+        let required = |name: &str| env::var(name)
+            .map_err(|_| format_err!("{} environment variable should be defined", name));
+        let database_url = || required("DATABASE_URL");
+
+        match var("STORAGE", "postgres".to_string())?.as_str() {
+            "postgres" => Ok(StorageConfig::Postgres {
+                url: database_url()?
+            }),
+            "filesystem" => Ok(StorageConfig::Filesystem {
+                path: var("STORAGE_PATH", PathBuf::from("data"))?
+            }),
+            "s3" => {
+                let credentials = match (env::var("S3_ACCESS_KEY"), env::var("S3_SECRET_KEY")) {
+                    (Ok(access_key), Ok(secret_key)) => Some((access_key, secret_key)),
+                    (Err(_), Err(_)) => None,
+                    _ => return Err(format_err!("Both S3_ACCESS_KEY and S3_SECRET_KEY should be defined"))
+                };
+
+                Ok(StorageConfig::S3 {
+                    url: database_url()?,
+                    bucket: required("S3_BUCKET")?,
+                    prefix: var("S3_PREFIX", String::new())?,
+                    region: var("S3_REGION", "us-east-1".to_string())?,
+                    endpoint: env::var("S3_ENDPOINT").ok(),
+                    credentials
+                })
+            },
+            other => Err(format_err!("Unknown storage: {}", other))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub preview: PreviewConfig,
+    pub upload: UploadConfig,
+    pub remote: RemoteConfig,
+    pub cache: CacheConfig,
+    pub storage: StorageConfig
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Error> {
+        Ok(Config {
+            preview: PreviewConfig::from_env()?,
+            upload: UploadConfig::from_env()?,
+            remote: RemoteConfig::from_env()?,
+            cache: CacheConfig::from_env()?,
+            storage: StorageConfig::from_env()?
+        })
+    }
+}
@@ -0,0 +1,122 @@
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use failure::Fail;
+use log::error;
+
+use crate::service::Response;
+
+
+/// Errors which are reported to clients.
+///
+/// Lower layers (models, storage) return `failure::Error`, errors
+/// of this type are recovered from them with `downcast`, anything
+/// else is considered to be internal error.
+#[derive(Debug, Fail)]
+pub enum ApiError {
+    #[fail(display = "{}", _0)]
+    BadRequest(String),
+    #[fail(display = "{}", _0)]
+    NotFound(String),
+    #[fail(display = "{}", _0)]
+    Forbidden(String),
+    #[fail(display = "{}", _0)]
+    UnsupportedFormat(String),
+    #[fail(display = "{}", _0)]
+    DecodeFailed(String),
+    /// image dimensions exceed configured limits
+    #[fail(display = "{}", _0)]
+    ImageTooLarge(String),
+    #[fail(display = "{}", _0)]
+    PayloadTooLarge(String),
+    #[fail(display = "{}", _0)]
+    RemoteFetchFailed(String),
+    #[fail(display = "{}", _0)]
+    StorageUnavailable(String),
+    #[fail(display = "{}", _0)]
+    Internal(String)
+}
+
+impl ApiError {
+    /// machine readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::UnsupportedFormat(_) => "unsupported_format",
+            ApiError::DecodeFailed(_) => "decode_failed",
+            ApiError::ImageTooLarge(_) => "image_too_large",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::RemoteFetchFailed(_) => "remote_fetch_failed",
+            ApiError::StorageUnavailable(_) => "storage_unavailable",
+            ApiError::Internal(_) => "internal"
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::DecodeFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::ImageTooLarge(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RemoteFetchFailed(_) => StatusCode::BAD_GATEWAY,
+            ApiError::StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    /// prepend context to error message, keeping its kind
+    pub fn context(self, context: &str) -> ApiError {
+        let wrap = |message: String| format!("{}: {}", context, message);
+        match self {
+            ApiError::BadRequest(m) => ApiError::BadRequest(wrap(m)),
+            ApiError::NotFound(m) => ApiError::NotFound(wrap(m)),
+            ApiError::Forbidden(m) => ApiError::Forbidden(wrap(m)),
+            ApiError::UnsupportedFormat(m) => ApiError::UnsupportedFormat(wrap(m)),
+            ApiError::DecodeFailed(m) => ApiError::DecodeFailed(wrap(m)),
+            ApiError::ImageTooLarge(m) => ApiError::ImageTooLarge(wrap(m)),
+            ApiError::PayloadTooLarge(m) => ApiError::PayloadTooLarge(wrap(m)),
+            ApiError::RemoteFetchFailed(m) => ApiError::RemoteFetchFailed(wrap(m)),
+            ApiError::StorageUnavailable(m) => ApiError::StorageUnavailable(wrap(m)),
+            ApiError::Internal(m) => ApiError::Internal(wrap(m))
+        }
+    }
+}
+
+impl From<failure::Error> for ApiError {
+    fn from(e: failure::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(e) => e,
+            Err(e) => ApiError::Internal(format!("{}", e))
+        }
+    }
+}
+
+impl From<BlockingError<failure::Error>> for ApiError {
+    fn from(e: BlockingError<failure::Error>) -> Self {
+        match e {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => ApiError::Internal("Thread pool is gone".to_string())
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(message) = self {
+            error!("Internal error: {}", message);
+        }
+
+        HttpResponse::build(self.status())
+            .json(Response::error(self))
+    }
+
+    /// default implementation replaces body with plain text message
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
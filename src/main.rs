@@ -1,36 +1,86 @@
+// impls generated by diesel and failure derives are not at the same level as their items
+#![allow(non_local_definitions)]
+// arguments are passed to format strings positionally
+#![allow(clippy::uninlined_format_args)]
+
 #[macro_use]
 extern crate diesel;
 
 use std::sync::Arc;
 
 use actix_web::{App, HttpServer};
+use actix_rt::{Arbiter, System};
 
-use crate::storage::PostgresStorage;
-use crate::service::{upload, preview};
+use crate::cache::ImageCache;
+use crate::config::{Config, StorageConfig};
+use crate::storage::{Storage, PostgresStorage, FilesystemStorage, S3Bucket, S3Storage};
+use crate::service::{upload, preview, download, info, variant, jobs, stats};
+use crate::service::upload::ImportQueue;
 
+mod cache;
+mod config;
+mod error;
 mod schema;
 mod storage;
 mod models;
 mod service;
 
 
-fn main() -> std::io::Result<()> {
-    dotenv::dotenv().ok();
-    env_logger::init();
-
-    let url = std::env::var("DATABASE_URL")
-        .expect("DATABASE_URL environment variable should be defined");
+fn run<S: Storage>(storage: S, config: Config) -> std::io::Result<()> {
+    // jobs are not resumed, so their items would never finish otherwise
+    match storage.interrupt_jobs() {
+        Ok(0) => {},
+        Ok(count) => log::info!("Marked {} interrupted job items as failed", count),
+        Err(e) => log::error!("Failed to mark interrupted job items as failed: {}", e)
+    }
 
-    let storage = PostgresStorage::new(&url)
-        .expect("Failed to initialize sqlite storage");
+    // system is created here, so import queue gets its own arbiter next to HTTP workers
+    let system = System::new("image-upload");
     let state = Arc::new(storage);
+    let queue = Arc::new(ImportQueue::<S>::start(&Arbiter::new(), config.remote.clone()));
+    let cache = Arc::new(ImageCache::from_config(&config.cache)
+        .expect("Failed to initialize cache"));
 
     HttpServer::new(move || {
         App::new()
             .data(state.clone())
-            .service(upload::bind::<PostgresStorage>("/images/upload"))
-            .service(preview::bind::<PostgresStorage>("/images"))
+            .service(upload::bind::<S>("/images/upload", config.upload.clone(), config.remote.clone(), queue.clone()))
+            .service(preview::bind::<S>("/images", config.preview.clone(), cache.clone()))
+            .service(download::bind::<S>("/images", cache.clone()))
+            .service(info::bind::<S>("/images"))
+            .service(variant::bind::<S>("/images", config.upload.variants.clone()))
+            .service(jobs::bind::<S>("/jobs"))
+            .service(stats::bind("/stats", cache.clone()))
         })
         .bind("127.0.0.1:8080")?
-        .run()
+        .start();
+    system.run()
+}
+
+fn main() -> std::io::Result<()> {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let config = Config::from_env()
+        .expect("Invalid configuration");
+
+    match config.storage.clone() {
+        StorageConfig::Postgres { url } => {
+            let storage = PostgresStorage::new(&url)
+                .expect("Failed to initialize postgres storage");
+            run(storage, config)
+        },
+        StorageConfig::Filesystem { path } => {
+            let storage = FilesystemStorage::open(&path)
+                .expect("Failed to initialize filesystem storage");
+            run(storage, config)
+        },
+        StorageConfig::S3 { url, bucket, prefix, region, endpoint, credentials } => {
+            let metadata = PostgresStorage::new(&url)
+                .expect("Failed to initialize postgres storage");
+            let bucket = S3Bucket::new(&bucket, &prefix, &region, endpoint.as_deref(), credentials)
+                .expect("Failed to initialize S3 client");
+            run(S3Storage::new(metadata, bucket), config)
+        }
+    }
 }
@@ -0,0 +1,23 @@
+//! AVIF encoder, images are never uploaded in this format,
+//! it is only used for previews and conversions
+
+use failure::Error;
+use image::{DynamicImage, GenericImageView};
+use rgb::FromSlice;
+
+/// from 1 to 100, similar to quality of JPEG
+const QUALITY: f32 = 70.0;
+/// from 1 (slowest, best compression) to 10 (fastest)
+const SPEED: u8 = 8;
+
+pub fn encode(image: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let (width, height) = image.dimensions();
+    let pixels = image.to_rgba().into_raw();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(QUALITY)
+        .with_alpha_quality(QUALITY)
+        .with_speed(SPEED)
+        .encode_rgba(ravif::Img::new(pixels.as_rgba(), width as usize, height as usize))?;
+    Ok(encoded.avif_file)
+}
@@ -1,10 +1,16 @@
+// variants are named like formats of `image` crate
+#![allow(clippy::upper_case_acronyms)]
+
 use std::fmt::{self, Display};
 use std::str::FromStr;
 
 use serde::{Serialize, Deserialize};
-use failure::{Error, format_err};
+use failure::Error;
 use enum_primitive_derive::Primitive;
 
+use crate::error::ApiError;
+use super::{qoi, svg};
+
 #[repr(i32)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Primitive, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -13,18 +19,132 @@ pub enum ImageFormat {
     JPEG = 2,
     GIF = 3,
     BMP = 4,
-    ICO = 5
+    ICO = 5,
+    WEBP = 6,
+    TIFF = 7,
+    TGA = 8,
+    PNM = 9,
+    QOI = 10,
+    /// only used as output format, there is no decoder for it
+    AVIF = 11,
+    /// only used as input format, rendered to raster images
+    SVG = 12
 }
 
 impl ImageFormat {
     pub fn from_content_type(content_type: &str) -> Result<ImageFormat, Error> {
         match content_type {
-            "image/png" => Ok(ImageFormat::PNG),
-            "image/jpeg" => Ok(ImageFormat::JPEG),
+            "image/png" | "image/x-png" => Ok(ImageFormat::PNG),
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Ok(ImageFormat::JPEG),
             "image/gif" => Ok(ImageFormat::GIF),
-            "image/bmp" => Ok(ImageFormat::BMP),
-            "image/ico" => Ok(ImageFormat::ICO),
-            other => Err(format_err!("Unknown image format: {}", other))
+            "image/bmp" | "image/x-bmp" | "image/x-ms-bmp" => Ok(ImageFormat::BMP),
+            "image/ico" | "image/x-icon" | "image/vnd.microsoft.icon" => Ok(ImageFormat::ICO),
+            "image/webp" => Ok(ImageFormat::WEBP),
+            "image/tiff" | "image/tiff-fx" | "image/x-tiff" => Ok(ImageFormat::TIFF),
+            "image/x-tga" | "image/x-targa" | "image/tga" | "image/targa" => Ok(ImageFormat::TGA),
+            "image/x-portable-anymap" | "image/x-portable-bitmap" | "image/x-portable-graymap"
+                | "image/x-portable-pixmap" | "image/x-portable-arbitrarymap" => Ok(ImageFormat::PNM),
+            "image/qoi" | "image/x-qoi" => Ok(ImageFormat::QOI),
+            "image/avif" => Ok(ImageFormat::AVIF),
+            "image/svg+xml" | "image/svg" => Ok(ImageFormat::SVG),
+            other => Err(ApiError::UnsupportedFormat(format!("Unknown image format: {}", other)).into())
+        }
+    }
+
+    pub fn content_type(self) -> String {
+        let content_type = match self {
+            ImageFormat::PNG => "image/png",
+            ImageFormat::JPEG => "image/jpeg",
+            ImageFormat::GIF => "image/gif",
+            ImageFormat::BMP => "image/bmp",
+            ImageFormat::ICO => "image/x-icon",
+            ImageFormat::WEBP => "image/webp",
+            ImageFormat::TIFF => "image/tiff",
+            ImageFormat::TGA => "image/x-tga",
+            ImageFormat::PNM => "image/x-portable-anymap",
+            ImageFormat::QOI => "image/qoi",
+            ImageFormat::AVIF => "image/avif",
+            ImageFormat::SVG => "image/svg+xml"
+        };
+
+        content_type.to_string()
+    }
+
+    /// whether images can be converted to this format,
+    /// `image` crate can only decode WebP, TIFF and TGA, raster images can't be converted to SVG
+    pub fn can_encode(self) -> bool {
+        !matches!(self, ImageFormat::WEBP | ImageFormat::TIFF | ImageFormat::TGA | ImageFormat::SVG)
+    }
+
+    /// whether encoded images keep alpha channel, JPEG and PNM encoders drop it
+    /// and alpha of BMP images is ignored by most viewers
+    pub fn has_alpha(self) -> bool {
+        !matches!(self, ImageFormat::JPEG | ImageFormat::BMP | ImageFormat::PNM)
+    }
+
+    /// format of derived images (e.g. variants), PNG is used if images can't be encoded in this format
+    pub fn derived(self) -> ImageFormat {
+        if self.can_encode() {
+            self
+        } else {
+            ImageFormat::PNG
+        }
+    }
+
+    /// whether images in this format can be uploaded
+    pub fn can_decode(self) -> bool {
+        self == ImageFormat::QOI || self == ImageFormat::SVG || self.decoder_format().is_some()
+    }
+
+    /// detect format by file signature, declared format (e.g. from Content-Type)
+    /// is only a hint, which is used when signature is not recognized (e.g. TGA doesn't have one)
+    pub fn detect(data: &[u8], declared: Option<ImageFormat>) -> Result<ImageFormat, Error> {
+        let detected = match image::guess_format(data) {
+            _ if qoi::is_qoi(data) => Some(ImageFormat::QOI),
+            Err(_) if svg::is_svg(data) => Some(ImageFormat::SVG),
+            Ok(image::ImageFormat::PNG) => Some(ImageFormat::PNG),
+            Ok(image::ImageFormat::JPEG) => Some(ImageFormat::JPEG),
+            Ok(image::ImageFormat::GIF) => Some(ImageFormat::GIF),
+            Ok(image::ImageFormat::BMP) => Some(ImageFormat::BMP),
+            Ok(image::ImageFormat::ICO) => Some(ImageFormat::ICO),
+            Ok(image::ImageFormat::WEBP) => Some(ImageFormat::WEBP),
+            Ok(image::ImageFormat::TIFF) => Some(ImageFormat::TIFF),
+            Ok(image::ImageFormat::PNM) => Some(ImageFormat::PNM),
+            Ok(other) => {
+                return Err(ApiError::UnsupportedFormat(format!("Unsupported image format: {:?}", other)).into())
+            },
+            Err(_) => None
+        };
+
+        let format = match (detected, declared) {
+            (Some(detected), Some(declared)) if detected != declared => {
+                return Err(ApiError::UnsupportedFormat(
+                    format!("Declared format {} doesn't match detected format {}", declared, detected)
+                ).into())
+            },
+            (Some(format), _) | (None, Some(format)) => format,
+            (None, None) => return Err(ApiError::UnsupportedFormat("Unable to detect image format".to_string()).into())
+        };
+
+        if !format.can_decode() {
+            return Err(ApiError::UnsupportedFormat(format!("Uploading {} images is not supported", format)).into());
+        }
+        Ok(format)
+    }
+
+    /// format for `image` crate decoder, QOI, AVIF and SVG are not supported by it
+    pub fn decoder_format(self) -> Option<image::ImageFormat> {
+        match self {
+            ImageFormat::PNG => Some(image::ImageFormat::PNG),
+            ImageFormat::JPEG => Some(image::ImageFormat::JPEG),
+            ImageFormat::GIF => Some(image::ImageFormat::GIF),
+            ImageFormat::BMP => Some(image::ImageFormat::BMP),
+            ImageFormat::ICO => Some(image::ImageFormat::ICO),
+            ImageFormat::WEBP => Some(image::ImageFormat::WEBP),
+            ImageFormat::TIFF => Some(image::ImageFormat::TIFF),
+            ImageFormat::TGA => Some(image::ImageFormat::TGA),
+            ImageFormat::PNM => Some(image::ImageFormat::PNM),
+            ImageFormat::QOI | ImageFormat::AVIF | ImageFormat::SVG => None
         }
     }
 }
@@ -36,7 +156,14 @@ impl Display for ImageFormat {
             ImageFormat::JPEG => "jpeg",
             ImageFormat::GIF => "gif",
             ImageFormat::BMP => "bmp",
-            ImageFormat::ICO => "ico"
+            ImageFormat::ICO => "ico",
+            ImageFormat::WEBP => "webp",
+            ImageFormat::TIFF => "tiff",
+            ImageFormat::TGA => "tga",
+            ImageFormat::PNM => "pnm",
+            ImageFormat::QOI => "qoi",
+            ImageFormat::AVIF => "avif",
+            ImageFormat::SVG => "svg"
         };
 
         write!(formatter, "{}", s)
@@ -46,38 +173,38 @@ impl Display for ImageFormat {
 impl FromStr for ImageFormat {
     type Err = Error;
 
+    /// names are case insensitive, so extensions like ".JPG" work too
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        match s.to_ascii_lowercase().as_str() {
             "png" => Ok(ImageFormat::PNG),
-            "jpeg" => Ok(ImageFormat::JPEG),
+            "jpeg" | "jpg" => Ok(ImageFormat::JPEG),
             "gif" => Ok(ImageFormat::GIF),
             "bmp" => Ok(ImageFormat::BMP),
             "ico" => Ok(ImageFormat::ICO),
-            other => Err(format_err!("Unknown image format: {}", other))
-        }
-    }
-}
-
-impl Into<image::ImageFormat> for ImageFormat {
-    fn into(self) -> image::ImageFormat {
-        match self {
-            ImageFormat::PNG => image::ImageFormat::PNG,
-            ImageFormat::JPEG => image::ImageFormat::JPEG,
-            ImageFormat::GIF => image::ImageFormat::GIF,
-            ImageFormat::BMP => image::ImageFormat::BMP,
-            ImageFormat::ICO => image::ImageFormat::ICO
+            "webp" => Ok(ImageFormat::WEBP),
+            "tiff" | "tif" => Ok(ImageFormat::TIFF),
+            "tga" => Ok(ImageFormat::TGA),
+            "pnm" | "pbm" | "pgm" | "ppm" | "pam" => Ok(ImageFormat::PNM),
+            "qoi" => Ok(ImageFormat::QOI),
+            "avif" => Ok(ImageFormat::AVIF),
+            "svg" => Ok(ImageFormat::SVG),
+            other => Err(ApiError::UnsupportedFormat(format!("Unknown image format: {}", other)).into())
         }
     }
 }
 
-impl Into<image::ImageOutputFormat> for ImageFormat {
-    fn into(self) -> image::ImageOutputFormat {
-        match self {
+impl From<ImageFormat> for image::ImageOutputFormat {
+    fn from(format: ImageFormat) -> Self {
+        match format {
             ImageFormat::PNG => image::ImageOutputFormat::PNG,
             ImageFormat::JPEG => image::ImageOutputFormat::JPEG(75 /* quality */),
             ImageFormat::GIF => image::ImageOutputFormat::GIF,
             ImageFormat::BMP => image::ImageOutputFormat::BMP,
-            ImageFormat::ICO => image::ImageOutputFormat::ICO
+            ImageFormat::ICO => image::ImageOutputFormat::ICO,
+            ImageFormat::PNM => image::ImageOutputFormat::PNM(
+                image::pnm::PNMSubtype::Pixmap(image::pnm::SampleEncoding::Binary)
+            ),
+            other => image::ImageOutputFormat::Unsupported(format!("{} encoding is not supported", other))
         }
     }
 }
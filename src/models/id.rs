@@ -1,10 +1,54 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
 use serde::{Serialize, Deserialize};
+use failure::Error;
+
+use crate::error::ApiError;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
-pub struct ImageId(pub i32);
+const ID_LENGTH: usize = 10;
+const MAX_ID_LENGTH: usize = 32;
+
+/// Public image identifier, random base62 string.
+/// It is used in urls instead of database key, so images can't be enumerated.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct ImageId(pub String);
 
 impl ImageId {
-    pub fn inner(self) -> i32 {
-        self.0
+    pub fn generate() -> ImageId {
+        let id = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(ID_LENGTH)
+            .collect();
+
+        ImageId(id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for ImageId {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", self.0)
+    }
+}
+
+impl FromStr for ImageId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_ID_LENGTH
+            && s.chars().all(|c| c.is_ascii_alphanumeric());
+
+        if !valid {
+            return Err(ApiError::NotFound(format!("Invalid image id: {}", s)).into());
+        }
+
+        Ok(ImageId(s.to_string()))
     }
 }
@@ -1,61 +1,262 @@
-use failure::Error;
+use chrono::{DateTime, Utc};
+use failure::{Error, bail};
+use image::{DynamicImage, GenericImageView};
 
+use crate::config::UploadConfig;
+use crate::error::ApiError;
 use super::format::ImageFormat;
+use super::{qoi, avif, svg};
+use super::metadata::{ImageMetadata, ImageSource};
+use super::resize::ResizeMode;
+use super::uploaded::UploadedImage;
+use super::variant::Variant;
 use crate::schema::images;
 
-#[derive(Queryable)]
-pub struct LoadedImage {
-    pub id: i32,
-    pub format: i32,
-    pub data: Vec<u8>
-}
-
 #[derive(Insertable)]
 #[table_name="images"]
 pub struct NewImage {
     pub format: i32,
-    pub data: Vec<u8>
+    pub delete_token: String,
+    pub public_id: String,
+    pub width: i32,
+    pub height: i32,
+    pub size: i64,
+    pub color_type: String,
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub source: String,
+    pub source_url: Option<String>,
+    pub blob_id: i32
+}
+
+/// vector images are rendered to cover `target` size if it's given
+fn decode(data: &[u8], format: ImageFormat, target: Option<(u32, u32)>) -> Result<DynamicImage, Error> {
+    match (format, format.decoder_format()) {
+        (_, Some(decoder_format)) => Ok(image::load_from_memory_with_format(data, decoder_format)?),
+        (ImageFormat::QOI, None) => qoi::decode(data),
+        (ImageFormat::SVG, None) => svg::rasterize(data, target),
+        (other, None) => bail!("Decoding {} images is not supported", other)
+    }
+}
+
+/// resize image for encoding in `format`, padding is opaque if format doesn't have alpha channel
+fn resize(image: &DynamicImage, size: (u32, u32), mode: ResizeMode, format: ImageFormat) -> DynamicImage {
+    let mode = if format.has_alpha() { mode } else { mode.opaque() };
+    mode.apply(image, size)
+}
+
+/// dimensions from image header, pixel data isn't decoded
+fn header_dimensions(data: &[u8], format: ImageFormat) -> Result<(u32, u32), Error> {
+    use std::io::Cursor;
+    use image::{ImageDecoder, ImageResult};
+
+    fn dimensions<D: ImageDecoder>(decoder: ImageResult<D>) -> Result<(u64, u64), Error> {
+        Ok(decoder?.dimensions())
+    }
+
+    let reader = Cursor::new(data);
+    let (width, height) = match format {
+        ImageFormat::PNG => dimensions(image::png::PNGDecoder::new(reader)),
+        ImageFormat::JPEG => dimensions(image::jpeg::JPEGDecoder::new(reader)),
+        ImageFormat::GIF => dimensions(image::gif::Decoder::new(reader)),
+        ImageFormat::BMP => dimensions(image::bmp::BMPDecoder::new(reader)),
+        ImageFormat::ICO => dimensions(image::ico::ICODecoder::new(reader)),
+        ImageFormat::WEBP => dimensions(image::webp::WebpDecoder::new(reader)),
+        ImageFormat::TIFF => dimensions(image::tiff::TIFFDecoder::new(reader)),
+        ImageFormat::TGA => dimensions(image::tga::TGADecoder::new(reader)),
+        ImageFormat::PNM => dimensions(image::pnm::PNMDecoder::new(reader)),
+        ImageFormat::QOI => return qoi::dimensions(data),
+        ImageFormat::AVIF | ImageFormat::SVG => bail!("Reading {} headers is not supported", format)
+    }?;
+
+    let clamp = |value: u64| value.min(u64::from(u32::MAX)) as u32;
+    Ok((clamp(width), clamp(height)))
+}
+
+fn check_dimensions((width, height): (u32, u32), limits: &UploadConfig) -> Result<(), Error> {
+    if width > limits.max_dimension || height > limits.max_dimension {
+        return Err(ApiError::ImageTooLarge(format!(
+            "Image is {}x{}, width and height can't exceed {}", width, height, limits.max_dimension
+        )).into());
+    }
+
+    if u64::from(width) * u64::from(height) > limits.max_pixels {
+        return Err(ApiError::ImageTooLarge(format!(
+            "Image is {}x{}, it can't have more than {} pixels", width, height, limits.max_pixels
+        )).into());
+    }
+    Ok(())
 }
 
+/// Image as it was uploaded by client.
+/// Original bytes are never re-encoded, decoding only happens
+/// for validation and when derived images (e.g. previews) are generated.
 #[derive(Clone)]
 pub struct Image {
     format: ImageFormat,
-    data: image::DynamicImage
+    data: Vec<u8>
 }
 
 impl Image {
-    pub fn decode(data: &[u8], format: ImageFormat) -> Result<Image, Error> {
-        let data = image::load_from_memory_with_format(data, format.into())?;
-        
-        Ok(Image {
+    /// wrap data which is known to be valid (e.g. loaded from storage)
+    pub fn from_raw(data: Vec<u8>, format: ImageFormat) -> Image {
+        Image {
             format,
             data
-        })
+        }
     }
-    
-    pub fn data(&self) -> Result<Vec<u8>, Error> {
-        let mut data = Vec::new();
-        let format: image::ImageOutputFormat = self.format.into();
-        self.data.write_to(&mut data, format)?;
-        Ok(data)
+
+    fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Image, Error> {
+        if !format.can_encode() {
+            return Err(ApiError::UnsupportedFormat(format!("Images can't be converted to {}", format)).into());
+        }
+
+        let write = |image: &DynamicImage| -> Result<Vec<u8>, Error> {
+            let mut data = Vec::new();
+            let output_format: image::ImageOutputFormat = format.into();
+            image.write_to(&mut data, output_format)?;
+            Ok(data)
+        };
+
+        let data = match format {
+            ImageFormat::QOI => qoi::encode(image),
+            ImageFormat::AVIF => avif::encode(image)?,
+            // PNM encoder doesn't support alpha channel
+            ImageFormat::PNM => write(&DynamicImage::ImageRgb8(image.to_rgb()))?,
+            _ => write(image)?
+        };
+        Ok(Image::from_raw(data, format))
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
     }
 
     pub fn format(&self) -> ImageFormat {
         self.format
     }
 
-    pub fn insertable(&self) -> Result<NewImage, Error> {
-        Ok(NewImage {
-            format: self.format as i32,
-            data: self.data()?
-        })
+    fn decoded(&self, target: Option<(u32, u32)>) -> Result<DynamicImage, Error> {
+        decode(&self.data, self.format, target)
     }
 
-    pub fn preview(&self, (width, height): (u32, u32)) -> Image {
-        Image {
-            format: self.format,
-            data: self.data.resize(width, height, image::FilterType::Lanczos3)
+    /// re-encode image in another format, original is returned as is if format matches
+    pub fn convert(self, format: ImageFormat) -> Result<Image, Error> {
+        if format == self.format {
+            return Ok(self);
         }
+
+        Image::encode(&self.decoded(None)?, format)
+    }
+
+    pub fn preview(&self, size: (u32, u32), mode: ResizeMode, format: ImageFormat) -> Result<Image, Error> {
+        let preview = resize(&self.decoded(Some(size))?, size, mode, format);
+        Image::encode(&preview, format)
+    }
+
+    pub fn variant(&self, variant: &Variant) -> Result<Image, Error> {
+        self.preview(variant.size, variant.mode, self.format.derived())
+    }
+
+    /// Generate all variants, raster images are decoded only once.
+    /// Vector images are rendered for each variant, so they stay sharp.
+    pub fn variants(&self, variants: &[Variant]) -> Result<Vec<(String, Image)>, Error> {
+        if self.format == ImageFormat::SVG {
+            return variants.iter()
+                .map(|variant| Ok((variant.name.clone(), self.variant(variant)?)))
+                .collect();
+        }
+
+        let decoded = self.decoded(None)?;
+        variants.iter()
+            .map(|variant| {
+                let preview = resize(&decoded, variant.size, variant.mode, self.format.derived());
+                Ok((variant.name.clone(), Image::encode(&preview, self.format.derived())?))
+            })
+            .collect()
     }
 }
 
+/// Image received from client together with its metadata
+#[derive(Clone)]
+pub struct Upload {
+    pub image: Image,
+    pub metadata: ImageMetadata,
+    /// named variants, which are stored together with image
+    pub variants: Vec<(String, Image)>
+}
+
+impl Upload {
+    /// Detect format of data (declared format is only a hint) and validate it.
+    /// Dimensions of raster images are checked before decoding, SVG documents are only parsed.
+    pub fn detect(
+        data: &[u8],
+        declared: Option<ImageFormat>,
+        source: ImageSource,
+        limits: &UploadConfig
+    ) -> Result<Upload, Error> {
+        let format = ImageFormat::detect(data, declared)?;
+        if format != ImageFormat::SVG {
+            let dimensions = header_dimensions(data, format)
+                .map_err(|e| ApiError::DecodeFailed(format!("Failed to decode image: {}", e)))?;
+            check_dimensions(dimensions, limits)?;
+        }
+
+        let upload = Upload::decode(data, format, source)?;
+        check_dimensions((upload.metadata.width, upload.metadata.height), limits)?;
+        Ok(upload)
+    }
+
+    /// Validate data by decoding it, original bytes are kept as is.
+    /// SVG images are sanitised instead, only the sanitised document is stored.
+    pub fn decode(data: &[u8], format: ImageFormat, source: ImageSource) -> Result<Upload, Error> {
+        let decoded = match format {
+            ImageFormat::SVG => svg::sanitize(data)
+                .and_then(|data| Ok((svg::size(&data)?, image::ColorType::RGBA(8), data))),
+            _ => decode(data, format, None)
+                .map(|decoded| (decoded.dimensions(), decoded.color(), data.to_vec()))
+        };
+        let (dimensions, color, data) = decoded
+            .map_err(|e| ApiError::DecodeFailed(format!("Failed to decode image: {}", e)))?;
+
+        Ok(Upload {
+            metadata: ImageMetadata::new(dimensions, color, &data, format, source),
+            image: Image::from_raw(data, format),
+            variants: Vec::new()
+        })
+    }
+
+    /// generate configured variants of image, so they don't have to be generated on every request
+    pub fn with_variants(self, variants: &[Variant]) -> Result<Upload, Error> {
+        if variants.is_empty() {
+            return Ok(self);
+        }
+
+        let variants = self.image.variants(variants)
+            .map_err(|e| ApiError::DecodeFailed(format!("Failed to generate variants: {}", e)))?;
+        Ok(Upload { variants, ..self })
+    }
+
+    /// row for image which data is stored in blob with given id
+    pub fn insertable(&self, uploaded: &UploadedImage, blob_id: i32) -> NewImage {
+        let metadata = &self.metadata;
+        NewImage {
+            format: self.image.format as i32,
+            delete_token: uploaded.delete_token.as_str().to_string(),
+            public_id: uploaded.id.as_str().to_string(),
+            width: metadata.width as i32,
+            height: metadata.height as i32,
+            size: metadata.size as i64,
+            color_type: metadata.color_type.clone(),
+            hash: metadata.hash.clone(),
+            created_at: metadata.created_at,
+            source: metadata.source.kind().to_string(),
+            source_url: metadata.source.url().map(str::to_string),
+            blob_id
+        }
+    }
+}
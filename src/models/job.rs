@@ -0,0 +1,250 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use failure::{Error, format_err};
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde::{Serialize, Deserialize};
+
+use crate::error::ApiError;
+use crate::schema::{jobs, job_items};
+use super::id::ImageId;
+use super::token::DeleteToken;
+use super::uploaded::UploadedImage;
+
+const ID_LENGTH: usize = 16;
+const MAX_ID_LENGTH: usize = 32;
+/// error code of items interrupted by restart
+const INTERRUPTED: &str = "interrupted";
+
+/// Public identifier of remote import job, random base62 string
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct JobId(pub String);
+
+impl JobId {
+    pub fn generate() -> JobId {
+        let id = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(ID_LENGTH)
+            .collect();
+
+        JobId(id)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for JobId {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", self.0)
+    }
+}
+
+impl FromStr for JobId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty()
+            && s.len() <= MAX_ID_LENGTH
+            && s.chars().all(|c| c.is_ascii_alphanumeric());
+
+        if !valid {
+            return Err(ApiError::NotFound(format!("Invalid job id: {}", s)).into());
+        }
+
+        Ok(JobId(s.to_string()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed"
+        }
+    }
+
+    pub fn is_finished(self) -> bool {
+        self == JobStatus::Succeeded || self == JobStatus::Failed
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            other => Err(format_err!("Unknown job status: {}", other))
+        }
+    }
+}
+
+/// Error which is reported to client, same as error response
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct JobError {
+    pub code: String,
+    pub message: String
+}
+
+/// Download of single location
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct JobItem {
+    pub url: String,
+    pub status: JobStatus,
+    /// set when item succeeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<UploadedImage>,
+    /// set when item failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>
+}
+
+impl JobItem {
+    pub fn new(url: String, status: JobStatus) -> JobItem {
+        JobItem {
+            url,
+            status,
+            image: None,
+            error: None
+        }
+    }
+
+    pub fn succeeded(url: String, image: UploadedImage) -> JobItem {
+        JobItem {
+            image: Some(image),
+            ..JobItem::new(url, JobStatus::Succeeded)
+        }
+    }
+
+    /// item which was queued or running when service was stopped
+    pub fn interrupted(url: String) -> JobItem {
+        JobItem {
+            error: Some(JobError {
+                code: INTERRUPTED.to_string(),
+                message: "Import was interrupted by restart of service".to_string()
+            }),
+            ..JobItem::new(url, JobStatus::Failed)
+        }
+    }
+
+    pub fn failed(url: String, e: &ApiError) -> JobItem {
+        JobItem {
+            error: Some(JobError {
+                code: e.code().to_string(),
+                message: format!("{}", e)
+            }),
+            ..JobItem::new(url, JobStatus::Failed)
+        }
+    }
+}
+
+/// Remote import which is processed in background
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: JobId,
+    pub created_at: DateTime<Utc>,
+    /// in order of locations in request
+    pub items: Vec<JobItem>
+}
+
+impl Job {
+    pub fn new(locations: Vec<String>) -> Job {
+        Job {
+            id: JobId::generate(),
+            created_at: Utc::now(),
+            items: locations.into_iter()
+                .map(|url| JobItem::new(url, JobStatus::Queued))
+                .collect()
+        }
+    }
+
+    /// Job is queued until any of its items is started and running until all of them are finished.
+    /// Finished job failed if any of its items failed.
+    pub fn status(&self) -> JobStatus {
+        let statuses = || self.items.iter().map(|item| item.status);
+        if statuses().all(|status| status == JobStatus::Queued) {
+            JobStatus::Queued
+        } else if !statuses().all(JobStatus::is_finished) {
+            JobStatus::Running
+        } else if statuses().any(|status| status == JobStatus::Failed) {
+            JobStatus::Failed
+        } else {
+            JobStatus::Succeeded
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="jobs"]
+pub struct NewJob<'a> {
+    pub public_id: &'a str,
+    pub created_at: DateTime<Utc>
+}
+
+/// Item of job as it is stored in database
+#[derive(Queryable, Insertable)]
+#[table_name="job_items"]
+pub struct JobItemRow {
+    pub job_id: i32,
+    pub position: i32,
+    pub url: String,
+    pub status: String,
+    pub image_id: Option<String>,
+    pub delete_token: Option<String>,
+    pub error_code: Option<String>,
+    pub error_message: Option<String>
+}
+
+impl JobItemRow {
+    pub fn new(job_id: i32, position: usize, item: &JobItem) -> JobItemRow {
+        JobItemRow {
+            job_id,
+            position: position as i32,
+            url: item.url.clone(),
+            status: item.status.as_str().to_string(),
+            image_id: item.image.as_ref().map(|image| image.id.as_str().to_string()),
+            delete_token: item.image.as_ref().map(|image| image.delete_token.as_str().to_string()),
+            error_code: item.error.as_ref().map(|error| error.code.clone()),
+            error_message: item.error.as_ref().map(|error| error.message.clone())
+        }
+    }
+
+    pub fn into_item(self) -> Result<JobItem, Error> {
+        let image = match (self.image_id, self.delete_token) {
+            (Some(id), Some(delete_token)) => Some(UploadedImage {
+                id: ImageId(id),
+                delete_token: DeleteToken(delete_token)
+            }),
+            _ => None
+        };
+        let error = match (self.error_code, self.error_message) {
+            (Some(code), Some(message)) => Some(JobError { code, message }),
+            _ => None
+        };
+
+        Ok(JobItem {
+            url: self.url,
+            status: self.status.parse()?,
+            image,
+            error
+        })
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, Utc};
+use failure::{Error, format_err};
+use num_traits::FromPrimitive;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use super::format::ImageFormat;
+use crate::schema::images;
+
+/// Where image came from
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    /// images uploaded before sources were recorded
+    Unknown,
+    Multipart,
+    Base64,
+    Remote {
+        url: String
+    }
+}
+
+impl ImageSource {
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            ImageSource::Unknown => "unknown",
+            ImageSource::Multipart => "multipart",
+            ImageSource::Base64 => "base64",
+            ImageSource::Remote { .. } => "remote"
+        }
+    }
+
+    pub(super) fn url(&self) -> Option<&str> {
+        match self {
+            ImageSource::Remote { url } => Some(url),
+            _ => None
+        }
+    }
+
+    fn from_parts(kind: &str, url: Option<String>) -> Result<ImageSource, Error> {
+        match (kind, url) {
+            ("unknown", _) => Ok(ImageSource::Unknown),
+            ("multipart", _) => Ok(ImageSource::Multipart),
+            ("base64", _) => Ok(ImageSource::Base64),
+            ("remote", Some(url)) => Ok(ImageSource::Remote { url }),
+            (kind, _) => Err(format_err!("Invalid image source: {}", kind))
+        }
+    }
+}
+
+/// Properties of original image, they are computed once on upload
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// size of original data in bytes
+    pub size: u64,
+    pub color_type: String,
+    /// hex encoded SHA-256 of original data
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub source: ImageSource
+}
+
+fn color_type_name(color: image::ColorType) -> String {
+    use image::ColorType::*;
+
+    match color {
+        Gray(bits) => format!("gray{}", bits),
+        GrayA(bits) => format!("graya{}", bits),
+        RGB(bits) => format!("rgb{}", bits),
+        RGBA(bits) => format!("rgba{}", bits),
+        BGR(bits) => format!("bgr{}", bits),
+        BGRA(bits) => format!("bgra{}", bits),
+        Palette(bits) => format!("palette{}", bits)
+    }
+}
+
+impl ImageMetadata {
+    pub fn new(
+        (width, height): (u32, u32),
+        color: image::ColorType,
+        data: &[u8],
+        format: ImageFormat,
+        source: ImageSource
+    ) -> ImageMetadata {
+        ImageMetadata {
+            format,
+            width,
+            height,
+            size: data.len() as u64,
+            color_type: color_type_name(color),
+            hash: format!("{:x}", Sha256::digest(data)),
+            created_at: Utc::now(),
+            source
+        }
+    }
+}
+
+/// Columns which are loaded into `MetadataRow`
+pub type MetadataColumns = (
+    images::format,
+    images::width,
+    images::height,
+    images::size,
+    images::color_type,
+    images::hash,
+    images::created_at,
+    images::source,
+    images::source_url
+);
+
+pub const METADATA_COLUMNS: MetadataColumns = (
+    images::format,
+    images::width,
+    images::height,
+    images::size,
+    images::color_type,
+    images::hash,
+    images::created_at,
+    images::source,
+    images::source_url
+);
+
+#[derive(Queryable)]
+pub struct MetadataRow {
+    pub format: i32,
+    pub width: i32,
+    pub height: i32,
+    pub size: i64,
+    pub color_type: String,
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub source: String,
+    pub source_url: Option<String>
+}
+
+impl MetadataRow {
+    pub fn into_metadata(self) -> Result<ImageMetadata, Error> {
+        let format = ImageFormat::from_i32(self.format)
+            .ok_or_else(|| format_err!("Unknown image format: {}", self.format))?;
+
+        Ok(ImageMetadata {
+            format,
+            width: self.width as u32,
+            height: self.height as u32,
+            size: self.size as u64,
+            color_type: self.color_type,
+            hash: self.hash,
+            created_at: self.created_at,
+            source: ImageSource::from_parts(&self.source, self.source_url)?
+        })
+    }
+}
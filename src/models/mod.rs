@@ -1,7 +1,22 @@
 mod image;
 mod id;
 mod format;
+mod resize;
+mod token;
+mod uploaded;
+mod metadata;
+mod job;
+mod variant;
+mod qoi;
+mod avif;
+mod svg;
 
-pub use self::image::{Image, NewImage, LoadedImage};
+pub use self::image::{Image, Upload};
 pub use self::id::ImageId;
 pub use self::format::ImageFormat;
+pub use self::resize::{ResizeMode, Color};
+pub use self::token::DeleteToken;
+pub use self::uploaded::UploadedImage;
+pub use self::metadata::{ImageMetadata, ImageSource, MetadataRow, METADATA_COLUMNS};
+pub use self::variant::{Variant, NewVariant};
+pub use self::job::{Job, JobId, JobItem, JobStatus, NewJob, JobItemRow};
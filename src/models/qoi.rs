@@ -0,0 +1,209 @@
+//! Codec for QOI ("Quite OK Image") format, which is not supported by `image` crate,
+//! see https://qoiformat.org/qoi-specification.pdf
+
+use failure::{Error, bail, format_err};
+use image::{DynamicImage, GenericImageView, RgbaImage, ColorType};
+
+const MAGIC: &[u8] = b"qoif";
+const HEADER_SIZE: usize = 14;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const OP_MASK: u8 = 0xc0;
+
+/// longest run which can be encoded with single OP_RUN
+const MAX_RUN: u8 = 62;
+
+fn hash(pixel: [u8; 4]) -> usize {
+    let [r, g, b, a] = pixel;
+    (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64
+}
+
+pub fn is_qoi(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// image size from header
+pub fn dimensions(data: &[u8]) -> Result<(u32, u32), Error> {
+    if data.len() < HEADER_SIZE + END_MARKER.len() || !is_qoi(data) {
+        bail!("Invalid QOI header");
+    }
+
+    let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+    Ok((width, height))
+}
+
+pub fn decode(data: &[u8]) -> Result<DynamicImage, Error> {
+    let (width, height) = dimensions(data)?;
+    let channels = data[12];
+    if channels != 3 && channels != 4 {
+        bail!("Invalid number of channels: {}", channels);
+    }
+
+    // single byte encodes at most MAX_RUN pixels, so data can't be much smaller
+    // than image, this also prevents huge allocations for malicious headers
+    let pixel_count = u64::from(width) * u64::from(height);
+    if pixel_count == 0 || pixel_count > data.len() as u64 * u64::from(MAX_RUN) {
+        bail!("Invalid QOI image size: {}x{}", width, height);
+    }
+
+    let size = pixel_count as usize * 4;
+    let end = data.len() - END_MARKER.len();
+    let mut pixels = Vec::with_capacity(size);
+    let mut index = [[0u8; 4]; 64];
+    let mut pixel = [0u8, 0, 0, 255];
+    let mut pos = HEADER_SIZE;
+    let mut run = 0;
+
+    while pixels.len() < size {
+        if run > 0 {
+            run -= 1;
+            pixels.extend_from_slice(&pixel);
+            continue;
+        }
+
+        let chunk = data.get(pos..end)
+            .filter(|chunk| !chunk.is_empty())
+            .ok_or_else(|| format_err!("Unexpected end of QOI data"))?;
+        let op = chunk[0];
+        let needed = match op {
+            OP_RGB => 4,
+            OP_RGBA => 5,
+            _ if op & OP_MASK == OP_LUMA => 2,
+            _ => 1
+        };
+        if chunk.len() < needed {
+            bail!("Unexpected end of QOI data");
+        }
+        pos += needed;
+
+        match op {
+            OP_RGB => pixel[..3].copy_from_slice(&chunk[1..4]),
+            OP_RGBA => pixel.copy_from_slice(&chunk[1..5]),
+            _ => match op & OP_MASK {
+                OP_INDEX => pixel = index[op as usize],
+                OP_DIFF => {
+                    pixel[0] = pixel[0].wrapping_add((op >> 4) & 0x03).wrapping_sub(2);
+                    pixel[1] = pixel[1].wrapping_add((op >> 2) & 0x03).wrapping_sub(2);
+                    pixel[2] = pixel[2].wrapping_add(op & 0x03).wrapping_sub(2);
+                },
+                OP_LUMA => {
+                    let dg = (op & 0x3f).wrapping_sub(32);
+                    pixel[0] = pixel[0].wrapping_add(dg).wrapping_add(chunk[1] >> 4).wrapping_sub(8);
+                    pixel[1] = pixel[1].wrapping_add(dg);
+                    pixel[2] = pixel[2].wrapping_add(dg).wrapping_add(chunk[1] & 0x0f).wrapping_sub(8);
+                },
+                // OP_RUN, current pixel is repeated (run + 1) times
+                _ => run = op & 0x3f
+            }
+        }
+
+        index[hash(pixel)] = pixel;
+        pixels.extend_from_slice(&pixel);
+    }
+
+    let image = RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| format_err!("Invalid QOI image size: {}x{}", width, height))?;
+
+    let image = DynamicImage::ImageRgba8(image);
+    Ok(match channels {
+        3 => DynamicImage::ImageRgb8(image.to_rgb()),
+        _ => image
+    })
+}
+
+pub fn encode(image: &DynamicImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let channels = match image.color() {
+        ColorType::GrayA(_) | ColorType::RGBA(_) | ColorType::BGRA(_) => 4,
+        _ => 3
+    };
+
+    let pixels = image.to_rgba();
+    let mut data = Vec::with_capacity(HEADER_SIZE + pixels.len() + END_MARKER.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&width.to_be_bytes());
+    data.extend_from_slice(&height.to_be_bytes());
+    data.push(channels);
+    data.push(0 /* sRGB with linear alpha */);
+
+    let mut index = [[0u8; 4]; 64];
+    let mut previous = [0u8, 0, 0, 255];
+    let mut run = 0;
+
+    for pixel in pixels.pixels() {
+        let pixel = pixel.data;
+        if pixel == previous {
+            run += 1;
+            if run == MAX_RUN {
+                data.push(OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+
+        if run > 0 {
+            data.push(OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        let position = hash(pixel);
+        if index[position] == pixel {
+            data.push(OP_INDEX | position as u8);
+        } else if pixel[3] != previous[3] {
+            data.extend_from_slice(&[OP_RGBA, pixel[0], pixel[1], pixel[2], pixel[3]]);
+        } else {
+            let dr = pixel[0].wrapping_sub(previous[0]) as i8;
+            let dg = pixel[1].wrapping_sub(previous[1]) as i8;
+            let db = pixel[2].wrapping_sub(previous[2]) as i8;
+            let dr_dg = dr.wrapping_sub(dg);
+            let db_dg = db.wrapping_sub(dg);
+
+            let small = |d: i8| (-2..=1).contains(&d);
+            if small(dr) && small(dg) && small(db) {
+                data.push(OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+            } else if (-32..=31).contains(&dg) && (-8..=7).contains(&dr_dg) && (-8..=7).contains(&db_dg) {
+                data.push(OP_LUMA | (dg + 32) as u8);
+                data.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+            } else {
+                data.extend_from_slice(&[OP_RGB, pixel[0], pixel[1], pixel[2]]);
+            }
+        }
+
+        index[position] = pixel;
+        previous = pixel;
+    }
+
+    if run > 0 {
+        data.push(OP_RUN | (run - 1));
+    }
+
+    data.extend_from_slice(&END_MARKER);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImageView;
+
+    const TEST_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/servo.png"));
+
+    #[test]
+    fn roundtrip() {
+        let image = image::load_from_memory(TEST_IMAGE).unwrap();
+        let encoded = super::encode(&image);
+        assert!(super::is_qoi(&encoded));
+
+        let decoded = super::decode(&encoded).unwrap();
+        assert_eq!(decoded.dimensions(), image.dimensions());
+        assert_eq!(decoded.to_rgba().into_raw(), image.to_rgba().into_raw());
+
+        assert!(super::decode(&encoded[..encoded.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use failure::{Error, format_err, bail};
+use image::{DynamicImage, GenericImageView, RgbaImage, Rgba, FilterType};
+
+const FILTER: FilterType = FilterType::Lanczos3;
+
+/// RGBA colour, parsed from "rrggbb" or "rrggbbaa" hex string
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    pub const WHITE: Color = Color([255, 255, 255, 255]);
+
+    /// opaque colour of this one drawn over white
+    pub fn over_white(self) -> Color {
+        let [r, g, b, a] = self.0;
+        let blend = |channel: u8| ((u32::from(channel) * u32::from(a) + 255 * (255 - u32::from(a)) + 127) / 255) as u8;
+        Color([blend(r), blend(g), blend(b), 255])
+    }
+}
+
+impl FromStr for Color {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid colour: {}", s);
+        }
+
+        let mut color = Color::WHITE;
+        for (i, channel) in color.0.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+
+        Ok(color)
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b, a] = self.0;
+        write!(formatter, "{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// how image is fitted into requested box
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum ResizeMode {
+    /// fit inside of the box, aspect ratio is preserved
+    #[default]
+    Fit,
+    /// cover the whole box, image is cropped at center
+    Fill,
+    /// fit inside of the box and fill the rest with background colour
+    Pad(Color),
+    /// resize to the box exactly, aspect ratio is not preserved
+    Stretch
+}
+
+/// same names as in requests, background of pad mode is appended to name (e.g. "pad-ffffffff")
+impl Display for ResizeMode {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResizeMode::Fit => write!(formatter, "fit"),
+            ResizeMode::Fill => write!(formatter, "fill"),
+            ResizeMode::Pad(background) => write!(formatter, "pad-{}", background),
+            ResizeMode::Stretch => write!(formatter, "stretch")
+        }
+    }
+}
+
+impl ResizeMode {
+    pub fn parse(name: &str, background: Option<Color>) -> Result<ResizeMode, Error> {
+        let mode = match name {
+            "fit" => ResizeMode::Fit,
+            "fill" | "cover" | "crop" => ResizeMode::Fill,
+            "pad" => ResizeMode::Pad(background.unwrap_or(Color::WHITE)),
+            "stretch" => ResizeMode::Stretch,
+            other => return Err(format_err!("Unknown resize mode: {}", other))
+        };
+
+        match mode {
+            ResizeMode::Pad(_) => {},
+            _ if background.is_some() => bail!("Background can only be used with pad mode"),
+            _ => {}
+        }
+
+        Ok(mode)
+    }
+
+    /// mode for formats without alpha channel, translucent background is drawn over white,
+    /// otherwise encoders would drop its alpha (e.g. transparent black would become black)
+    pub fn opaque(self) -> ResizeMode {
+        match self {
+            ResizeMode::Pad(background) => ResizeMode::Pad(background.over_white()),
+            mode => mode
+        }
+    }
+
+    pub fn apply(self, image: &DynamicImage, (width, height): (u32, u32)) -> DynamicImage {
+        match self {
+            ResizeMode::Fit => image.resize(width, height, FILTER),
+            ResizeMode::Fill => image.resize_to_fill(width, height, FILTER),
+            ResizeMode::Stretch => image.resize_exact(width, height, FILTER),
+            ResizeMode::Pad(Color(background)) => {
+                let fitted = image.resize(width, height, FILTER);
+                let (fitted_width, fitted_height) = fitted.dimensions();
+                let mut canvas = RgbaImage::from_pixel(width, height, Rgba(background));
+                image::imageops::overlay(
+                    &mut canvas,
+                    &fitted.to_rgba(),
+                    (width - fitted_width) / 2,
+                    (height - fitted_height) / 2
+                );
+                DynamicImage::ImageRgba8(canvas)
+            }
+        }
+    }
+}
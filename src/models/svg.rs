@@ -0,0 +1,239 @@
+//! SVG support: uploaded documents are sanitised by re-serializing only SVG elements
+//! and attributes which can't run scripts or load external resources,
+//! raster images are rendered with `resvg`
+
+use failure::{Error, bail, format_err};
+use image::{DynamicImage, RgbaImage};
+use resvg::{usvg, tiny_skia};
+use resvg::usvg::{TreeParsing, TreeTextToPath};
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// elements which run scripts, embed foreign content or can change attributes (e.g. `href`) at runtime
+const FORBIDDEN_ELEMENTS: &[&str] = &[
+    "script", "foreignObject", "handler", "listener",
+    "set", "animate", "animateColor", "animateMotion", "animateTransform", "discard"
+];
+
+/// only raster images can be embedded with `data:` URLs
+const DATA_URL_PREFIXES: &[&str] = &["data:image/png", "data:image/jpeg", "data:image/gif"];
+
+/// rendered images are scaled down to fit into this size
+const MAX_RASTER_SIZE: f32 = 8192.0;
+
+thread_local! {
+    /// loading system fonts is slow, so it's done once per thread
+    static FONTS: usvg::fontdb::Database = {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        fonts
+    };
+}
+
+/// there is no signature, so document is expected to start with some markup and contain `<svg` element
+pub fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(4096)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// reference to element of the same document or embedded raster image
+fn is_safe_reference(value: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase();
+    value.starts_with('#') || DATA_URL_PREFIXES.iter().any(|prefix| value.starts_with(prefix))
+}
+
+/// whether CSS (or presentation attribute like `fill`) loads anything except safe references,
+/// escapes are rejected as they can be used to hide `url(`
+fn has_unsafe_url(value: &str) -> bool {
+    let value = value.to_ascii_lowercase();
+    if value.contains("@import") || value.contains('\\') {
+        return true;
+    }
+
+    value.match_indices("url(").any(|(position, _)| {
+        let target = value[position + 4..].trim_start().trim_start_matches(['"', '\'']);
+        !is_safe_reference(target)
+    })
+}
+
+fn escape(value: &str, output: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            c => output.push(c)
+        }
+    }
+}
+
+fn write_element(node: roxmltree::Node<'_, '_>, output: &mut String) {
+    let name = node.tag_name().name();
+    if node.tag_name().namespace() != Some(SVG_NS) || FORBIDDEN_ELEMENTS.contains(&name) {
+        return;
+    }
+
+    if name == "style" {
+        let css: String = node.children().filter_map(|child| child.text()).collect();
+        if has_unsafe_url(&css) {
+            return;
+        }
+    }
+
+    output.push('<');
+    output.push_str(name);
+    if node.parent_element().is_none() {
+        output.push_str(&format!(r#" xmlns="{}" xmlns:xlink="{}""#, SVG_NS, XLINK_NS));
+    }
+
+    for attribute in node.attributes() {
+        let attribute_name = match attribute.namespace() {
+            None => attribute.name().to_string(),
+            Some(XLINK_NS) => format!("xlink:{}", attribute.name()),
+            Some(XML_NS) => format!("xml:{}", attribute.name()),
+            Some(_) => continue
+        };
+
+        let value = attribute.value();
+        let unsafe_reference = attribute.name() == "href" && !is_safe_reference(value);
+        let event_handler = attribute_name.to_ascii_lowercase().starts_with("on");
+        if unsafe_reference || event_handler || has_unsafe_url(value) {
+            continue;
+        }
+
+        output.push(' ');
+        output.push_str(&attribute_name);
+        output.push_str("=\"");
+        escape(value, output);
+        output.push('"');
+    }
+
+    if !node.has_children() {
+        output.push_str("/>");
+        return;
+    }
+
+    output.push('>');
+    for child in node.children() {
+        if child.is_element() {
+            write_element(child, output);
+        } else if let Some(text) = child.text() {
+            escape(text, output);
+        }
+    }
+    output.push_str("</");
+    output.push_str(name);
+    output.push('>');
+}
+
+/// Document with only safe content, comments, processing instructions and DTD are dropped too.
+/// Internal entities are expanded, roxmltree limits their expansion.
+/// Result is validated by parsing it.
+pub fn sanitize(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let options = roxmltree::ParsingOptions { allow_dtd: true, ..roxmltree::ParsingOptions::default() };
+    let document = roxmltree::Document::parse_with_options(std::str::from_utf8(data)?, options)?;
+    let root = document.root_element();
+    if root.tag_name().namespace() != Some(SVG_NS) || root.tag_name().name() != "svg" {
+        bail!("Root element is not <svg>");
+    }
+
+    let mut output = String::with_capacity(data.len());
+    write_element(root, &mut output);
+
+    let output = output.into_bytes();
+    parse(&output)?;
+    Ok(output)
+}
+
+fn parse(data: &[u8]) -> Result<usvg::Tree, Error> {
+    Ok(usvg::Tree::from_data(data, &usvg::Options::default())?)
+}
+
+/// intrinsic size of image, rounded up
+pub fn size(data: &[u8]) -> Result<(u32, u32), Error> {
+    let size = parse(data)?.size;
+    Ok((size.width().ceil() as u32, size.height().ceil() as u32))
+}
+
+/// Render image so that it covers `target` size, which avoids upscaling of raster previews.
+/// Intrinsic size is used when there is no target.
+pub fn rasterize(data: &[u8], target: Option<(u32, u32)>) -> Result<DynamicImage, Error> {
+    let mut tree = parse(data)?;
+    FONTS.with(|fonts| tree.convert_text(fonts));
+
+    let (width, height) = (tree.size.width(), tree.size.height());
+    let scale = match target {
+        Some((target_width, target_height)) => (target_width as f32 / width).max(target_height as f32 / height),
+        None => 1.0
+    };
+    let scale = scale.min(MAX_RASTER_SIZE / width).min(MAX_RASTER_SIZE / height);
+    let (width, height) = ((width * scale).ceil() as u32, (height * scale).ceil() as u32);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| format_err!("Invalid SVG image size: {}x{}", width, height))?;
+    resvg::Tree::from_usvg(&tree).render(tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // pixmap uses premultiplied alpha
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        pixels.extend_from_slice(&[color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+
+    let image = RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| format_err!("Invalid SVG image size: {}x{}", width, height))?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImageView;
+
+    const LOGO: &str = r##"<?xml version="1.0"?>
+<!DOCTYPE svg [<!ENTITY color "#ff0000">]>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"
+     xmlns:html="http://www.w3.org/1999/xhtml" width="40" height="20" onload="alert(1)">
+  <script>alert(2)</script>
+  <style>@import url(https://example.com/style.css);</style>
+  <defs><rect id="box" width="20" height="20" fill="&color;"/></defs>
+  <use xlink:href="#box"/>
+  <image href="https://example.com/tracker.png" width="1" height="1"/>
+  <a href="javascript:alert(3)"><circle cx="30" cy="10" r="10" fill="url(https://example.com/#g)"/></a>
+  <foreignObject><html:script>alert(4)</html:script></foreignObject>
+  <set attributeName="href" to="javascript:alert(5)"/>
+  <html:iframe src="https://example.com"/>
+</svg>"##;
+
+    #[test]
+    fn sanitize() {
+        assert!(super::is_svg(LOGO.as_bytes()));
+        assert!(!super::is_svg(b"\x89PNG\r\n"));
+
+        let sanitized = String::from_utf8(super::sanitize(LOGO.as_bytes()).unwrap()).unwrap();
+        for forbidden in &["script", "alert", "example.com", "onload", "foreignObject", "iframe", "<set", "DOCTYPE"] {
+            assert!(!sanitized.contains(forbidden), "{} is not removed from {}", forbidden, sanitized);
+        }
+        assert!(sanitized.contains(r##"xlink:href="#box""##));
+        assert!(sanitized.contains(r##"fill="#ff0000""##));
+
+        assert!(super::sanitize(b"<html><svg/></html>").is_err());
+        assert!(super::sanitize(b"<svg xmlns=\"http://www.w3.org/2000/svg\"").is_err());
+    }
+
+    #[test]
+    fn rasterize() {
+        let sanitized = super::sanitize(LOGO.as_bytes()).unwrap();
+        assert_eq!(super::size(&sanitized).unwrap(), (40, 20));
+
+        let image = super::rasterize(&sanitized, None).unwrap();
+        assert_eq!(image.dimensions(), (40, 20));
+        assert_eq!(image.get_pixel(10, 10).data, [255, 0, 0, 255]);
+
+        let image = super::rasterize(&sanitized, Some((100, 100))).unwrap();
+        assert_eq!(image.dimensions(), (200, 100));
+    }
+}
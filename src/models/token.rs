@@ -0,0 +1,39 @@
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde::{Serialize, Deserialize};
+
+const TOKEN_LENGTH: usize = 32;
+
+/// Secret which is given to uploader and is required to delete an image
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct DeleteToken(pub String);
+
+impl DeleteToken {
+    pub fn generate() -> DeleteToken {
+        let token = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .collect();
+
+        DeleteToken(token)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// constant time comparison, so token can't be guessed by timing
+    pub fn matches(&self, token: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let actual = token.as_bytes();
+
+        // images stored before delete tokens were introduced can't be deleted
+        if expected.is_empty() || expected.len() != actual.len() {
+            return false;
+        }
+
+        expected.iter()
+            .zip(actual.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
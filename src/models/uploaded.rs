@@ -0,0 +1,21 @@
+use serde::{Serialize, Deserialize};
+
+use super::id::ImageId;
+use super::token::DeleteToken;
+
+/// Result of successful upload, returned to uploader
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct UploadedImage {
+    pub id: ImageId,
+    pub delete_token: DeleteToken
+}
+
+impl UploadedImage {
+    /// generate new id and delete token for image which is about to be stored
+    pub fn generate() -> UploadedImage {
+        UploadedImage {
+            id: ImageId::generate(),
+            delete_token: DeleteToken::generate()
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::str::FromStr;
+
+use failure::{Error, bail};
+
+use crate::config::parse_size;
+use crate::schema::variants;
+use super::resize::ResizeMode;
+
+/// last segments of other image routes, variants can't use them
+const RESERVED_NAMES: &[&str] = &["preview", "info"];
+
+/// Named preview which is generated on upload and stored next to original image,
+/// parsed from "{name}={width}x{height}" or "{name}={width}x{height}:{mode}"
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub size: (u32, u32),
+    pub mode: ResizeMode
+}
+
+impl FromStr for Variant {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = match s.find('=') {
+            Some(pos) => (s[..pos].trim(), &s[pos + 1..]),
+            None => bail!("Invalid variant {}, it should be name=size[:mode]", s)
+        };
+
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !valid || RESERVED_NAMES.contains(&name) {
+            bail!("Invalid variant name: {}", name);
+        }
+
+        let (size, mode) = match spec.find(':') {
+            Some(pos) => (&spec[..pos], ResizeMode::parse(spec[pos + 1..].trim(), None)?),
+            None => (spec, ResizeMode::Fit)
+        };
+        let size = parse_size(size)?;
+        if size.0 == 0 || size.1 == 0 {
+            bail!("Size of variant {} can't be empty", name);
+        }
+
+        Ok(Variant {
+            name: name.to_string(),
+            size,
+            mode
+        })
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="variants"]
+pub struct NewVariant<'a> {
+    pub blob_id: i32,
+    pub name: &'a str,
+    pub format: i32,
+    pub object_key: String,
+    /// None if data is stored outside of database
+    pub data: Option<Vec<u8>>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Variant;
+    use crate::models::ResizeMode;
+
+    #[test]
+    fn parse() {
+        let variant: Variant = "thumb=100x80:cover".parse().unwrap();
+        assert_eq!(variant, Variant { name: "thumb".to_string(), size: (100, 80), mode: ResizeMode::Fill });
+        let variant: Variant = " medium = 800".parse().unwrap();
+        assert_eq!(variant, Variant { name: "medium".to_string(), size: (800, 800), mode: ResizeMode::Fit });
+
+        for invalid in &["thumb", "=100x100", "Thumb=100", "preview=100", "thumb=0x100", "thumb=100:zoom"] {
+            assert!(invalid.parse::<Variant>().is_err(), "{} should be invalid", invalid);
+        }
+    }
+}
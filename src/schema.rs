@@ -1,7 +1,70 @@
+table! {
+    blobs (blob_id) {
+        blob_id -> Int4,
+        hash -> Varchar,
+        object_key -> Varchar,
+        data -> Nullable<Bytea>,
+        refcount -> Int4,
+    }
+}
+
 table! {
     images (image_id) {
         image_id -> Int4,
         format -> Int4,
-        data -> Bytea,
+        delete_token -> Varchar,
+        public_id -> Varchar,
+        width -> Int4,
+        height -> Int4,
+        size -> Int8,
+        color_type -> Varchar,
+        hash -> Varchar,
+        created_at -> Timestamptz,
+        source -> Varchar,
+        source_url -> Nullable<Text>,
+        blob_id -> Int4,
+    }
+}
+
+table! {
+    job_items (job_id, position) {
+        job_id -> Int4,
+        position -> Int4,
+        url -> Text,
+        status -> Varchar,
+        image_id -> Nullable<Varchar>,
+        delete_token -> Nullable<Varchar>,
+        error_code -> Nullable<Varchar>,
+        error_message -> Nullable<Text>,
+    }
+}
+
+table! {
+    jobs (job_id) {
+        job_id -> Int4,
+        public_id -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    variants (blob_id, name) {
+        blob_id -> Int4,
+        name -> Varchar,
+        format -> Int4,
+        object_key -> Varchar,
+        data -> Nullable<Bytea>,
     }
 }
+
+joinable!(images -> blobs (blob_id));
+joinable!(job_items -> jobs (job_id));
+joinable!(variants -> blobs (blob_id));
+
+allow_tables_to_appear_in_same_query!(
+    blobs,
+    images,
+    job_items,
+    jobs,
+    variants,
+);
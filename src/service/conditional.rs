@@ -0,0 +1,98 @@
+//! Validators and conditional requests. Stored images are immutable,
+//! so validators only depend on image and on transformation of it.
+
+use actix_web::HttpRequest;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::header;
+use chrono::{DateTime, Utc};
+use sha2::{Sha256, Digest};
+
+use crate::models::{ImageId, ImageMetadata};
+
+/// images never change, so they can be cached for a year
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Validators of response
+pub struct Validators {
+    /// strong entity tag with quotes
+    etag: String,
+    last_modified: DateTime<Utc>
+}
+
+impl Validators {
+    /// Tag of original image is hash of its data, tag of derived image
+    /// is hash of original hash and transformation (e.g. preview parameters).
+    /// Hash of images stored in S3 before metadata was added is unknown, their id is used instead
+    pub fn new(id: &ImageId, metadata: &ImageMetadata, transformation: Option<&str>) -> Validators {
+        let original = match metadata.hash.as_str() {
+            "" => format!("id-{}", id),
+            hash => hash.to_string()
+        };
+        let tag = match transformation {
+            Some(transformation) => format!("{:x}", Sha256::digest(format!("{}/{}", original, transformation).as_bytes())),
+            None => original
+        };
+
+        Validators {
+            etag: format!("\"{}\"", tag),
+            last_modified: metadata.created_at
+        }
+    }
+
+    /// headers are sent with both full and 304 responses
+    pub fn apply(&self, response: &mut HttpResponseBuilder) {
+        response
+            .header(header::ETAG, self.etag.as_str())
+            .header(header::LAST_MODIFIED, self.last_modified.format(HTTP_DATE).to_string())
+            .header(header::CACHE_CONTROL, CACHE_CONTROL);
+    }
+}
+
+/// Conditional headers of request, they are extracted before request is handled in thread pool
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<DateTime<Utc>>,
+    if_range: Option<String>
+}
+
+impl Conditions {
+    pub fn from_request(request: &HttpRequest) -> Conditions {
+        let value = |name| request.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok());
+
+        Conditions {
+            if_none_match: value(header::IF_NONE_MATCH).map(str::to_string),
+            // invalid dates are ignored
+            if_modified_since: value(header::IF_MODIFIED_SINCE)
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+            if_range: value(header::IF_RANGE).map(str::to_string)
+        }
+    }
+
+    /// Whether client already has response with these validators.
+    /// If-Modified-Since is ignored when If-None-Match is present, tags are compared weakly.
+    pub fn is_fresh(&self, validators: &Validators) -> bool {
+        match (&self.if_none_match, self.if_modified_since) {
+            (Some(tags), _) => tags.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == validators.etag),
+            (None, Some(since)) => validators.last_modified.timestamp() <= since.timestamp(),
+            (None, None) => false
+        }
+    }
+
+    /// Range is applied if If-Range is missing or matches validators,
+    /// tag is compared strongly, date has to be equal to Last-Modified
+    pub fn range_applies(&self, validators: &Validators) -> bool {
+        match &self.if_range {
+            None => true,
+            Some(tag) if tag.starts_with('"') => *tag == validators.etag,
+            Some(date) => DateTime::parse_from_rfc2822(date)
+                .map(|date| date.timestamp() == validators.last_modified.timestamp())
+                .unwrap_or(false)
+        }
+    }
+}
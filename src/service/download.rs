@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::{header, Method};
+use futures::future::{self, Future, Either};
+use failure::Error;
+use serde::Deserialize;
+
+use crate::cache::ImageCache;
+use crate::error::ApiError;
+use crate::storage::Storage;
+use crate::models::{ImageId, Image, ImageFormat, ImageMetadata};
+use super::negotiation::OutputFormat;
+use super::conditional::{Conditions, Validators};
+use super::range::{self, Ranges};
+
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    format: Option<String>
+}
+
+/// header with delete token, `Authorization: Bearer <token>` is accepted too
+const DELETE_TOKEN_HEADER: &str = "X-Delete-Token";
+
+/// What is sent after validators are checked
+enum Content {
+    NotModified,
+    /// original data, it is streamed from storage and ranges can be requested
+    Original(Ranges),
+    /// converted image (or original of unknown size) is always sent whole
+    Whole(Image)
+}
+
+/// split "{id}.{ext}" into id and optional extension
+fn parse_path(path: &str) -> Result<(ImageId, Option<&str>), Error> {
+    let (id, ext) = match path.rfind('.') {
+        Some(pos) => (&path[..pos], Some(&path[pos + 1..])),
+        None => (path, None)
+    };
+
+    Ok((id.parse::<ImageId>()?, ext))
+}
+
+/// extension works the same way as format query parameter,
+/// they are compared as formats, so e.g. ".jpg" matches "?format=jpeg"
+fn requested_format(ext: Option<&str>, query: &DownloadQuery) -> Result<Option<ImageFormat>, Error> {
+    let ext_format = ext.map(str::parse::<ImageFormat>).transpose()?;
+    let query_format = query.format.as_ref().map(|format| format.parse::<ImageFormat>()).transpose()?;
+
+    match (ext_format, query_format) {
+        (Some(ext_format), Some(query_format)) if ext_format != query_format => {
+            let message = format!("Extension {} doesn't match requested format {}", ext_format, query_format);
+            Err(ApiError::BadRequest(message).into())
+        },
+        (ext_format, query_format) => Ok(ext_format.or(query_format))
+    }
+}
+
+fn download_image<S>(
+    state: web::Data<Arc<S>>,
+    info: web::Path<(String,)>,
+    query: web::Query<DownloadQuery>,
+    request: HttpRequest
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let (id, ext) = match parse_path(&info.0) {
+        Ok(parsed) => parsed,
+        Err(e) => return Either::B(future::err(e.into()))
+    };
+
+    let output = match requested_format(ext, &query) {
+        Ok(format) => OutputFormat::with_format(&request, format),
+        Err(e) => return Either::B(future::err(e.into()))
+    };
+
+    let negotiated = output.is_negotiated();
+    let conditions = Conditions::from_request(&request);
+    let requested_range = request.headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let head = request.method() == Method::HEAD;
+    let storage = state.get_ref().clone();
+    Either::A(
+        // image isn't loaded if client already has it, original is streamed later
+        web::block({
+            let (storage, id) = (storage.clone(), id.clone());
+            move || -> Result<(ImageMetadata, Validators, Content), Error> {
+                let metadata = storage.metadata(&id)?;
+                let format = output.choose(metadata.format);
+                let transformation = Some(format.to_string()).filter(|_| format != metadata.format);
+                let validators = Validators::new(&id, &metadata, transformation.as_deref());
+                // size of images which were stored in S3 before metadata was added is unknown (0),
+                // so ranges can't be computed for them
+                let content = if conditions.is_fresh(&validators) {
+                    Content::NotModified
+                } else if transformation.is_none() && metadata.size > 0 {
+                    let range = requested_range.as_deref()
+                        .filter(|_| conditions.range_applies(&validators));
+                    Content::Original(Ranges::parse(range, metadata.size))
+                } else {
+                    Content::Whole(storage.load(&id)?.convert(format)?)
+                };
+
+                Ok((metadata, validators, content))
+            }
+        })
+        .map_err(|e| ApiError::from(e).context("Failed to load image"))
+        .map(move |(metadata, validators, content)| {
+            let mut response = match content {
+                Content::NotModified => HttpResponse::NotModified(),
+                _ => HttpResponse::Ok()
+            };
+            validators.apply(&mut response);
+            if negotiated {
+                response.header(header::VARY, "Accept");
+            }
+
+            let format = match content {
+                Content::Whole(ref image) => image.format(),
+                _ => metadata.format
+            };
+            // sanitised documents shouldn't run anything, this is a second line of defence
+            if format == ImageFormat::SVG {
+                response.header(header::CONTENT_SECURITY_POLICY, "default-src 'none'; style-src 'unsafe-inline'; img-src data:");
+            }
+
+            match content {
+                Content::NotModified => response.finish(),
+                Content::Original(ranges) => range::respond(response, storage, id, &metadata, ranges, head),
+                Content::Whole(image) => {
+                    response
+                        .content_type(image.format().content_type())
+                        .content_length(image.data().len() as u64);
+                    if head {
+                        response.finish()
+                    } else {
+                        response.body(image.into_data())
+                    }
+                }
+            }
+        })
+    )
+}
+
+/// Token is sent in header rather than in query string,
+/// so it doesn't end up in access logs and browser history
+fn delete_token(request: &HttpRequest) -> Option<String> {
+    let value = |name| request.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim);
+
+    let bearer = || value("Authorization")
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].trim());
+
+    value(DELETE_TOKEN_HEADER)
+        .or_else(bearer)
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+/// derived images of deleted image are removed from cache
+fn delete_image<S>(
+    state: web::Data<Arc<S>>,
+    cache: web::Data<Arc<ImageCache>>,
+    info: web::Path<(String,)>,
+    request: HttpRequest
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let id = match parse_path(&info.0) {
+        Ok((id, None)) => id,
+        Ok((_, Some(_))) | Err(_) => {
+            return Either::B(future::err(ApiError::NotFound(format!("No image {}", info.0))))
+        }
+    };
+
+    let token = match delete_token(&request) {
+        Some(token) => token,
+        None => return Either::B(future::err(ApiError::BadRequest("Missing delete token".to_string())))
+    };
+
+    let storage = state.get_ref().clone();
+    let cache = cache.get_ref().clone();
+    Either::A(
+        web::block(move || -> Result<(), Error> {
+            storage.delete(&id, &token)?;
+            cache.invalidate(&id);
+            Ok(())
+        })
+        .map_err(ApiError::from)
+        .map(|_| HttpResponse::NoContent().finish())
+    )
+}
+
+pub fn bind<S>(prefix: &str, cache: Arc<ImageCache>) -> impl HttpServiceFactory
+    where S: Storage
+{
+    // NOTE: "{id}" also matches "{id}.{ext}", extension is parsed by handler
+    let path = prefix.to_string() + "/{id}";
+    web::resource(&path)
+        .data(cache)
+        .route(web::get().to_async(download_image::<S>))
+        .route(web::head().to_async(download_image::<S>))
+        .route(web::delete().to_async(delete_image::<S>))
+}
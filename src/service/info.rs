@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{web, HttpResponse};
+use futures::future::{self, Future, Either};
+use failure::Error;
+
+use crate::error::ApiError;
+use crate::storage::Storage;
+use crate::models::ImageId;
+use super::{Response, ImageInfo};
+
+
+/// metadata of image, it is available without downloading image itself
+fn image_info<S>(
+    state: web::Data<Arc<S>>,
+    info: web::Path<(String,)>
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let id = match info.0.parse::<ImageId>() {
+        Ok(id) => id,
+        Err(e) => return Either::B(future::err(e.into()))
+    };
+
+    let storage = state.get_ref().clone();
+    Either::A(
+        web::block(move || -> Result<ImageInfo, Error> {
+            let metadata = storage.metadata(&id)?;
+            Ok(ImageInfo { id, metadata })
+        })
+        .map_err(ApiError::from)
+        .map(|info| HttpResponse::Ok().json(Response::Info(info)))
+    )
+}
+
+pub fn bind<S>(prefix: &str) -> impl HttpServiceFactory
+    where S: Storage
+{
+    let path = prefix.to_string() + "/{id}/info";
+    web::resource(&path)
+        .route(web::get().to_async(image_info::<S>))
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{web, HttpResponse};
+use futures::future::{self, Future, Either};
+
+use crate::error::ApiError;
+use crate::storage::Storage;
+use crate::models::JobId;
+use super::{Response, JobInfo};
+
+
+/// state of remote import job, clients poll it until job is finished
+fn job_status<S>(
+    state: web::Data<Arc<S>>,
+    info: web::Path<(String,)>
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let id = match info.0.parse::<JobId>() {
+        Ok(id) => id,
+        Err(e) => return Either::B(future::err(e.into()))
+    };
+
+    let storage = state.get_ref().clone();
+    Either::A(
+        web::block(move || storage.load_job(&id))
+            .map_err(ApiError::from)
+            .map(|job| HttpResponse::Ok().json(Response::Job(JobInfo::new(job))))
+    )
+}
+
+pub fn bind<S>(prefix: &str) -> impl HttpServiceFactory
+    where S: Storage
+{
+    let path = prefix.to_string() + "/{id}";
+    web::resource(&path)
+        .route(web::get().to_async(job_status::<S>))
+}
@@ -1,35 +1,108 @@
 pub mod upload;
 pub mod preview;
+pub mod download;
+pub mod info;
+pub mod jobs;
+pub mod variant;
+pub mod stats;
+mod negotiation;
+mod conditional;
+mod range;
+mod remote;
 mod request;
 mod response;
 
 pub use request::{Request, Base64Image};
-pub use response::Response;
+pub use response::{Response, UploadResult, ImageInfo, JobInfo};
 
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
+    use std::time::{Duration, Instant};
    
     use actix_web::{test, App};
+    use actix_web::dev::HttpServiceFactory;
+    use actix_web::http::Method;
+    use actix_rt::Arbiter;
+    use image::GenericImageView;
 
+    use crate::cache::ImageCache;
+    use crate::config::{PreviewConfig, UploadConfig, RemoteConfig};
     use crate::storage::{Storage, MemoryStorage};
-    use crate::models::{Image, ImageId, ImageFormat};
-    use super::{upload, preview, Request, Base64Image, Response};
+    use crate::models::{ImageId, ImageFormat, ImageSource, Upload, ResizeMode, Color, JobStatus};
+    use super::{upload, preview, download, info, variant, jobs, stats, Request, Base64Image, Response, UploadResult, JobInfo};
 
     const TEST_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/servo.png"));
 
+    fn test_upload() -> Upload {
+        Upload::decode(TEST_IMAGE, ImageFormat::PNG, ImageSource::Multipart).unwrap()
+    }
+
+    fn storage_with_image(upload: Upload) -> (Arc<MemoryStorage>, ImageId) {
+        let storage = MemoryStorage::default();
+        let uploaded = storage.store(vec![upload]).unwrap().remove(0);
+        (Arc::new(storage), uploaded.id)
+    }
+
+    fn test_cache() -> Arc<ImageCache> {
+        Arc::new(ImageCache::default())
+    }
+
+    /// queue consumer runs on its own arbiter, test system has to exist before it is created
+    fn import_queue(remote: RemoteConfig) -> Arc<upload::ImportQueue<MemoryStorage>> {
+        let arbiter = test::run_on(Arbiter::new);
+        Arc::new(upload::ImportQueue::start(&arbiter, remote))
+    }
+
+    /// upload service with default configuration
+    fn upload_service(path: &'static str) -> impl HttpServiceFactory {
+        upload::bind::<MemoryStorage>(path, UploadConfig::default(), RemoteConfig::default(), import_queue(RemoteConfig::default()))
+    }
+
+    /// multipart/form-data request with each item in separate field
+    fn multipart_request(uri: &str, items: &[&[u8]]) -> test::TestRequest {
+        let items: Vec<_> = items.iter().map(|item| ("application/octet-stream", *item)).collect();
+        typed_multipart_request(uri, &items)
+    }
+
+    /// multipart/form-data request with declared content type of each item
+    fn typed_multipart_request(uri: &str, items: &[(&str, &[u8])]) -> test::TestRequest {
+        let mut body = Vec::new();
+        for (content_type, item) in items {
+            body.extend_from_slice(b"--boundary\r\n\
+                Content-Disposition: form-data; name=\"file\"; filename=\"image\"\r\n");
+            body.extend(format!("Content-Type: {}\r\n\r\n", content_type).bytes());
+            body.extend_from_slice(item);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--boundary--\r\n");
+
+        test::TestRequest::post()
+            .uri(uri)
+            .header("Content-Type", "multipart/form-data; boundary=boundary")
+            .set_payload(body)
+    }
+
+    fn uploaded_ids(response: Response) -> Vec<ImageId> {
+        match response {
+            Response::Uploaded(images) => images.into_iter().map(|image| image.id).collect(),
+            other => panic!("Unexpected response: {:?}", other)
+        }
+    }
+
     #[test]
     fn upload_base64() {
         let storage = Arc::new(MemoryStorage::default());
         let mut app = test::init_service(
             App::new()
                 .data(storage.clone())
-                .service(upload::bind::<MemoryStorage>("/upload"))
+                .service(upload_service("/upload"))
         );
         
+        // format is detected from data
         let image = Base64Image {
-            format: ImageFormat::PNG,
+            format: None,
             data: base64::encode(TEST_IMAGE)
         };
 
@@ -41,24 +114,27 @@ mod tests {
             .to_request();
 
         let response: Response = test::read_response_json(&mut app, request);
-        assert_eq!(response, Response::Ids(vec![ImageId(0)]));
-        assert!(storage.load(ImageId(0)).is_ok());
+        let ids = uploaded_ids(response);
+        assert_eq!(ids.len(), 1);
+        assert_eq!(storage.load(&ids[0]).unwrap().data(), TEST_IMAGE);
     }
 
     #[test]
     fn upload_from_link() {
+        // mock server is local, such addresses are denied by default
+        let remote = RemoteConfig { allow_private: true, ..RemoteConfig::default() };
         let storage = Arc::new(MemoryStorage::default());
         let mut app = test::init_service(
             App::new()
                 .data(storage.clone())
-                .service(upload::bind::<MemoryStorage>("/upload"))
+                .service(upload::bind::<MemoryStorage>("/upload", UploadConfig::default(), remote.clone(), import_queue(remote)))
         );
 
         let url = mockito::server_url();
-        let request = test::TestRequest::post()
+        let request = || test::TestRequest::post()
             .uri("/upload")
             .set_json(&Request::Remote {
-               locations: vec![url] 
+               locations: vec![url.clone(), format!("{}/raw", url)]
             })
             .to_request();
 
@@ -67,16 +143,33 @@ mod tests {
             .with_header("Content-Type", "image/png")
             .with_body(TEST_IMAGE)
             .create();
-        
-        let response: Response = test::read_response_json(&mut app, request);
-        assert_eq!(response, Response::Ids(vec![ImageId(0)]));
-        assert!(storage.load(ImageId(0)).is_ok());
+        // format is detected from data when content type is generic
+        let _raw = mockito::mock("GET", "/raw")
+            .with_status(200)
+            .with_header("Content-Type", "application/octet-stream")
+            .with_body(TEST_IMAGE)
+            .create();
+
+        let response: Response = test::read_response_json(&mut app, request());
+        let ids = uploaded_ids(response);
+        assert_eq!(ids.len(), 2);
+        assert!(storage.load(&ids[0]).is_ok());
+        assert!(storage.load(&ids[1]).is_ok());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/upload"))
+        );
+        let response = test::call_service(&mut app, request());
+        assert_eq!(response.status(), 403);
     }
 
     #[test]
     fn generate_preview() {
-        let image = Image::decode(TEST_IMAGE, ImageFormat::PNG).unwrap();
-        let storage = Arc::new(MemoryStorage::with_image(image.clone()));
+        let upload = test_upload();
+        let image = upload.image.clone();
+        let (storage, id) = storage_with_image(upload);
 
         let mut app = test::init_service(
             App::new()
@@ -84,12 +177,12 @@ mod tests {
                 // NOTE: upload service is not required for this test,
                 //       but it is included to make sure that routing
                 //       works as expected
-                .service(upload::bind::<MemoryStorage>("/images/upload"))
-                .service(preview::bind::<MemoryStorage>("/images"))
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
         );
 
         let request = test::TestRequest::get()
-            .uri("/images/0/preview")
+            .uri(&format!("/images/{}/preview", id))
             .to_request();
 
         let response = test::call_service(&mut app, request);
@@ -101,9 +194,1142 @@ mod tests {
         assert_eq!(content_type, "image/png");
         
         let body = test::read_body(response);
-        let preview = Image::decode(&body, ImageFormat::PNG).unwrap();
-        assert_eq!(preview.format(), ImageFormat::PNG);
-        assert_eq!(preview.data().unwrap(), image.preview((100, 100)).data().unwrap());
+        assert!(image::load_from_memory_with_format(&body, image::ImageFormat::PNG).is_ok());
+        assert_eq!(&body[..], image.preview((100, 100), ResizeMode::Fit, ImageFormat::PNG).unwrap().data());
     }
-}
 
+    #[test]
+    fn generate_preview_with_size() {
+        let upload = test_upload();
+        let image = upload.image.clone();
+        let (storage, id) = storage_with_image(upload);
+        let config = PreviewConfig {
+            allowed_sizes: vec![(50, 50), (64, 32)],
+            ..PreviewConfig::default()
+        };
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(preview::bind::<MemoryStorage>("/images", config, test_cache()))
+        );
+
+        for (query, size) in &[("w=64&h=32", (64, 32)), ("size=50", (50, 50)), ("w=50", (50, 50))] {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?{}", id, query))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert!(response.status().is_success());
+
+            let body = test::read_body(response);
+            assert_eq!(&body[..], image.preview(*size, ResizeMode::Fit, ImageFormat::PNG).unwrap().data());
+        }
+
+        for query in &["size=2000", "size=10", "w=100&h=100", "size=50&w=50", "h=40"] {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?{}", id, query))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert_eq!(response.status(), 400);
+        }
+    }
+
+    #[test]
+    fn generate_preview_with_one_side() {
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(200, 100))
+            .write_to(&mut data, image::ImageOutputFormat::PNG)
+            .unwrap();
+        let (storage, id) = storage_with_image(Upload::decode(&data, ImageFormat::PNG, ImageSource::Multipart).unwrap());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+        );
+
+        // other side isn't limited by requested one, modes other than fit keep aspect ratio too
+        let cases = [("w=50", (50, 25)), ("h=50", (100, 50)), ("w=50&mode=fill", (50, 25)), ("h=1000", (1024, 512))];
+        for (query, dimensions) in &cases {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?{}", id, query))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert!(response.status().is_success());
+
+            let preview = image::load_from_memory(&test::read_body(response)).unwrap();
+            assert_eq!(preview.dimensions(), *dimensions, "{}", query);
+        }
+    }
+
+    #[test]
+    fn generate_preview_with_mode() {
+        let upload = test_upload();
+        let image = upload.image.clone();
+        let (storage, id) = storage_with_image(upload);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+        );
+
+        let modes = [
+            ("mode=fill", ResizeMode::Fill),
+            ("mode=cover", ResizeMode::Fill),
+            ("mode=stretch", ResizeMode::Stretch),
+            ("mode=pad", ResizeMode::Pad(Color::WHITE)),
+            ("mode=pad&bg=ff000080", ResizeMode::Pad(Color([255, 0, 0, 128])))
+        ];
+
+        for (query, mode) in &modes {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?w=64&h=32&{}", id, query))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert!(response.status().is_success());
+
+            let body = test::read_body(response);
+            let preview = image.preview((64, 32), *mode, ImageFormat::PNG).unwrap();
+            assert_eq!(&body[..], preview.data());
+
+            let (width, height) = image::load_from_memory(&body).unwrap().dimensions();
+            assert_eq!((width, height), (64, 32));
+        }
+
+        // translucent background is drawn over white for formats without alpha channel
+        let formats: [(&str, [u8; 4]); 3] = [("png", [255, 0, 0, 128]), ("jpeg", [255, 127, 127, 255]), ("bmp", [255, 127, 127, 255])];
+        for (format, corner) in &formats {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?w=64&h=32&mode=pad&bg=ff000080&format={}", id, format))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert!(response.status().is_success());
+
+            let preview = image::load_from_memory(&test::read_body(response)).unwrap().to_rgba();
+            let pixel = preview.get_pixel(0, 0).data;
+            for (actual, expected) in pixel.iter().zip(corner) {
+                assert!((i32::from(*actual) - i32::from(*expected)).abs() <= 2, "{} corner is {:?}", format, pixel);
+            }
+        }
+
+        for query in &["mode=zoom", "mode=fit&bg=ffffff", "mode=pad&bg=white"] {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?{}", id, query))
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert_eq!(response.status(), 400);
+        }
+    }
+
+    #[test]
+    fn download_original() {
+        let (storage, id) = storage_with_image(test_upload());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        for uri in &[format!("/images/{}", id), format!("/images/{}.png", id)] {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .to_request();
+
+            let response = test::call_service(&mut app, request);
+            assert!(response.status().is_success());
+
+            let content_type = response.headers()
+                .get("Content-Type").unwrap()
+                .to_str().unwrap();
+            assert_eq!(content_type, "image/png");
+
+            let body = test::read_body(response);
+            assert_eq!(&body[..], TEST_IMAGE);
+        }
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}.foo", id))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 415);
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}.png?format=jpeg", id))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn convert_format() {
+        let (storage, id) = storage_with_image(test_upload());
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let cases = [
+            ("/images/{id}?format=jpeg", None, "image/jpeg"),
+            ("/images/{id}.bmp", None, "image/bmp"),
+            ("/images/{id}.qoi", None, "image/qoi"),
+            ("/images/{id}?format=ppm", None, "image/x-portable-anymap"),
+            ("/images/{id}.jpg?format=jpeg", None, "image/jpeg"),
+            ("/images/{id}.JPEG?format=jpg", None, "image/jpeg"),
+            ("/images/{id}", Some("image/webp, image/gif;q=0.9"), "image/gif"),
+            ("/images/{id}", Some("image/gif, image/*;q=0.8"), "image/gif"),
+            ("/images/{id}", Some("image/gif;q=0.5, image/png"), "image/png"),
+            ("/images/{id}/preview?format=jpeg", None, "image/jpeg"),
+            ("/images/{id}/preview", Some("image/bmp"), "image/bmp")
+        ];
+
+        for (uri, accept, expected) in &cases {
+            let uri = uri.replace("{id}", id.as_str());
+            let mut request = test::TestRequest::get().uri(&uri);
+            if let Some(accept) = accept {
+                request = request.header("Accept", *accept);
+            }
+
+            let response = test::call_service(&mut app, request.to_request());
+            assert!(response.status().is_success());
+
+            let content_type = response.headers()
+                .get("Content-Type").unwrap()
+                .to_str().unwrap();
+            assert_eq!(content_type, *expected);
+
+            let format = ImageFormat::from_content_type(expected).unwrap();
+            let body = test::read_body(response);
+            assert!(Upload::decode(&body, format, ImageSource::Unknown).is_ok());
+        }
+    }
+
+    #[test]
+    fn error_codes() {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let not_found = test::TestRequest::get()
+            .uri("/images/42/preview")
+            .to_request();
+
+        let bad_image = test::TestRequest::post()
+            .uri("/images/upload")
+            .set_json(&Request::Base64 {
+                images: vec![Base64Image {
+                    format: Some(ImageFormat::PNG),
+                    data: base64::encode(b"definitely not a png")
+                }]
+            })
+            .to_request();
+
+        let bad_base64 = test::TestRequest::post()
+            .uri("/images/upload")
+            .set_json(&Request::Base64 {
+                images: vec![Base64Image {
+                    format: Some(ImageFormat::PNG),
+                    data: "%%%".to_string()
+                }]
+            })
+            .to_request();
+
+        let wrong_format = test::TestRequest::post()
+            .uri("/images/upload")
+            .set_json(&Request::Base64 {
+                images: vec![Base64Image {
+                    format: Some(ImageFormat::JPEG),
+                    data: base64::encode(TEST_IMAGE)
+                }]
+            })
+            .to_request();
+
+        let cases = vec![
+            (not_found, 404, "not_found"),
+            (bad_image, 422, "decode_failed"),
+            (bad_base64, 400, "bad_request"),
+            (wrong_format, 415, "unsupported_format")
+        ];
+
+        for (request, status, expected_code) in cases {
+            let response = test::call_service(&mut app, request);
+            assert_eq!(response.status(), status);
+
+            let body = test::read_body(response);
+            match serde_json::from_slice::<Response>(&body).unwrap() {
+                Response::Error { code, .. } => assert_eq!(code, expected_code),
+                other => panic!("Unexpected response: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn delete_image() {
+        let storage = Arc::new(MemoryStorage::default());
+        let uploaded = storage.store(vec![test_upload()]).unwrap().remove(0);
+
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let uri = format!("/images/{}", uploaded.id);
+
+        // token in query string is ignored
+        let request = test::TestRequest::with_uri(&format!("{}?token={}", uri, uploaded.delete_token.as_str()))
+            .method(Method::DELETE)
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 400);
+
+        let request = test::TestRequest::with_uri(&uri)
+            .method(Method::DELETE)
+            .header("X-Delete-Token", "wrong")
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 403);
+        assert!(storage.load(&uploaded.id).is_ok());
+
+        let request = test::TestRequest::with_uri(&uri)
+            .method(Method::DELETE)
+            .header("X-Delete-Token", uploaded.delete_token.as_str())
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 204);
+        assert!(storage.load(&uploaded.id).is_err());
+
+        let request = test::TestRequest::with_uri(&uri)
+            .method(Method::DELETE)
+            .header("Authorization", format!("Bearer {}", uploaded.delete_token.as_str()))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn image_info() {
+        let (storage, id) = storage_with_image(test_upload());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+                .service(info::bind::<MemoryStorage>("/images"))
+        );
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}/info", id))
+            .to_request();
+
+        let info = match test::read_response_json(&mut app, request) {
+            Response::Info(info) => info,
+            other => panic!("Unexpected response: {:?}", other)
+        };
+
+        let dimensions = image::load_from_memory(TEST_IMAGE).unwrap().dimensions();
+        assert_eq!(info.id, id);
+        assert_eq!(info.metadata.format, ImageFormat::PNG);
+        assert_eq!((info.metadata.width, info.metadata.height), dimensions);
+        assert_eq!(info.metadata.size, TEST_IMAGE.len() as u64);
+        assert_eq!(info.metadata.hash.len(), 64);
+        assert_eq!(info.metadata.source, ImageSource::Multipart);
+
+        let request = test::TestRequest::get()
+            .uri("/images/missing/info")
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn deduplicate_uploads() {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+        );
+
+        let mut uploaded = Vec::new();
+        for _ in 0..2 {
+            let request = test::TestRequest::post()
+                .uri("/images/upload")
+                .set_json(&Request::Base64 {
+                    images: vec![Base64Image {
+                        format: Some(ImageFormat::PNG),
+                        data: base64::encode(TEST_IMAGE)
+                    }]
+                })
+                .to_request();
+
+            match test::read_response_json(&mut app, request) {
+                Response::Uploaded(images) => uploaded.extend(images),
+                other => panic!("Unexpected response: {:?}", other)
+            }
+        }
+
+        // each upload gets its own id and delete token, but data is stored once
+        assert_ne!(uploaded[0].id, uploaded[1].id);
+        assert_eq!(storage.blob_count(), 1);
+
+        storage.delete(&uploaded[0].id, uploaded[0].delete_token.as_str()).unwrap();
+        assert_eq!(storage.load(&uploaded[1].id).unwrap().data(), TEST_IMAGE);
+        assert_eq!(storage.blob_count(), 1);
+
+        storage.delete(&uploaded[1].id, uploaded[1].delete_token.as_str()).unwrap();
+        assert_eq!(storage.blob_count(), 0);
+    }
+
+    #[test]
+    fn upload_formats() {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let original = test_upload().image;
+        for format in &[ImageFormat::JPEG, ImageFormat::GIF, ImageFormat::BMP, ImageFormat::PNM, ImageFormat::QOI] {
+            let data = original.clone().convert(*format).unwrap().into_data();
+            let request = test::TestRequest::post()
+                .uri("/images/upload")
+                .set_json(&Request::Base64 {
+                    images: vec![Base64Image {
+                        format: None,
+                        data: base64::encode(&data)
+                    }]
+                })
+                .to_request();
+
+            let ids = uploaded_ids(test::read_response_json(&mut app, request));
+            assert_eq!(storage.metadata(&ids[0]).unwrap().format, *format);
+
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}", ids[0]))
+                .to_request();
+            let response = test::call_service(&mut app, request);
+            let content_type = response.headers()
+                .get("Content-Type").unwrap()
+                .to_str().unwrap();
+            assert_eq!(content_type, format.content_type());
+            assert_eq!(&test::read_body(response)[..], &data[..]);
+        }
+
+        // TGA has no magic bytes, it is only accepted with declared content type
+        let tga: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/gradient.tga"));
+        let request = typed_multipart_request("/images/upload", &[("image/x-tga", tga)]).to_request();
+        let ids = uploaded_ids(test::read_response_json(&mut app, request));
+        assert_eq!(storage.metadata(&ids[0]).unwrap().format, ImageFormat::TGA);
+        let request = multipart_request("/images/upload", &[tga]).to_request();
+        assert_eq!(test::call_service(&mut app, request).status(), 415);
+
+        // images can't be converted to formats without encoder
+        let (storage, id) = storage_with_image(test_upload());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}.webp", id))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 415);
+    }
+
+    #[test]
+    fn decode_only_formats() {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let cases: Vec<(&[u8], ImageFormat, (u32, u32))> = vec![
+            (include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/python.webp")), ImageFormat::WEBP, (16, 16)),
+            (include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/gradient.tiff")), ImageFormat::TIFF, (24, 16)),
+            (include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/gradient.tga")), ImageFormat::TGA, (24, 16))
+        ];
+
+        for (data, format, dimensions) in cases {
+            let base64 = test::TestRequest::post()
+                .uri("/images/upload")
+                .set_json(&Request::Base64 {
+                    images: vec![Base64Image {
+                        format: Some(format),
+                        data: base64::encode(data)
+                    }]
+                })
+                .to_request();
+            let multipart = typed_multipart_request("/images/upload", &[(format.content_type().as_str(), data)]).to_request();
+
+            for request in [base64, multipart] {
+                let id = uploaded_ids(test::read_response_json(&mut app, request)).remove(0);
+                let metadata = storage.metadata(&id).unwrap();
+                assert_eq!(metadata.format, format);
+                assert_eq!((metadata.width, metadata.height), dimensions);
+
+                // original is served as uploaded, previews are converted
+                let request = test::TestRequest::get().uri(&format!("/images/{}", id)).to_request();
+                assert_eq!(&test::read_body(test::call_service(&mut app, request))[..], data);
+                let request = test::TestRequest::get().uri(&format!("/images/{}/preview?size=16&format=png", id)).to_request();
+                let preview = image::load_from_memory(&test::read_body(test::call_service(&mut app, request))).unwrap();
+
+                // WebP decoder of image crate only decodes luma, so WebP images are grayscale
+                assert_eq!(metadata.color_type == "gray8", format == ImageFormat::WEBP);
+                let gray = matches!(preview.color(), image::ColorType::Gray(_) | image::ColorType::GrayA(_));
+                assert_eq!(gray, format == ImageFormat::WEBP);
+            }
+        }
+    }
+
+    #[test]
+    fn avif_output() {
+        let (storage, id) = storage_with_image(test_upload());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage)
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let cases = [
+            ("/images/{id}?format=avif", None),
+            ("/images/{id}.avif", None),
+            ("/images/{id}/preview?format=avif", None),
+            ("/images/{id}/preview", Some("image/avif, image/png;q=0.5"))
+        ];
+
+        for (uri, accept) in &cases {
+            let uri = uri.replace("{id}", id.as_str());
+            let mut request = test::TestRequest::get().uri(&uri);
+            if let Some(accept) = accept {
+                request = request.header("Accept", *accept);
+            }
+
+            let response = test::call_service(&mut app, request.to_request());
+            assert!(response.status().is_success());
+
+            let content_type = response.headers()
+                .get("Content-Type").unwrap()
+                .to_str().unwrap();
+            assert_eq!(content_type, "image/avif");
+
+            // AVIF is stored in ISO base media file, which starts with "ftyp" box
+            let body = test::read_body(response);
+            assert_eq!(&body[4..12], b"ftypavif");
+        }
+    }
+
+    #[test]
+    fn svg_upload() {
+        const LOGO: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="64" height="32">
+            <script>alert(1)</script>
+            <rect width="64" height="32" fill="blue"/>
+        </svg>"#;
+
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let request = test::TestRequest::post()
+            .uri("/images/upload")
+            .set_json(&Request::Base64 {
+                images: vec![Base64Image {
+                    format: None,
+                    data: base64::encode(LOGO)
+                }]
+            })
+            .to_request();
+
+        let ids = uploaded_ids(test::read_response_json(&mut app, request));
+        let metadata = storage.metadata(&ids[0]).unwrap();
+        assert_eq!(metadata.format, ImageFormat::SVG);
+        assert_eq!((metadata.width, metadata.height), (64, 32));
+
+        // only sanitised document is stored
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}", ids[0]))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "image/svg+xml");
+        assert!(response.headers().contains_key("Content-Security-Policy"));
+        let body = test::read_body(response);
+        assert!(!String::from_utf8_lossy(&body).contains("script"));
+
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}.png", ids[0]))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        let image = image::load_from_memory_with_format(&test::read_body(response), image::ImageFormat::PNG).unwrap();
+        assert_eq!(image.dimensions(), (64, 32));
+        assert_eq!(image.get_pixel(32, 16).data, [0, 0, 255, 255]);
+
+        // previews are rendered to raster format
+        let request = test::TestRequest::get()
+            .uri(&format!("/images/{}/preview", ids[0]))
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert!(response.status().is_success());
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "image/png");
+
+        // documents without <svg> root can't be sanitised
+        let request = test::TestRequest::post()
+            .uri("/images/upload")
+            .set_json(&Request::Base64 {
+                images: vec![Base64Image {
+                    format: Some(ImageFormat::SVG),
+                    data: base64::encode("<html><svg/></html>")
+                }]
+            })
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 422);
+    }
+
+    #[test]
+    fn upload_limits() {
+        let base64_request = |count: usize| {
+            let images = (0..count)
+                .map(|_| Base64Image { format: None, data: base64::encode(TEST_IMAGE) })
+                .collect();
+            test::TestRequest::post()
+                .uri("/images/upload")
+                .set_json(&Request::Base64 { images })
+                .to_request()
+        };
+
+        let multipart = || multipart_request("/images/upload", &[TEST_IMAGE]).to_request();
+        let invalid_items = || multipart_request("/images/upload?partial=true", &[&[0u8; 600], &[0u8; 600]]).to_request();
+
+        let default = UploadConfig::default();
+        let cases = vec![
+            (UploadConfig { max_file_size: 1000, ..default.clone() }, base64_request(1), 413, "payload_too_large"),
+            (UploadConfig { max_file_size: 1000, ..default.clone() }, multipart(), 413, "payload_too_large"),
+            (UploadConfig { max_request_size: 1000, ..default.clone() }, base64_request(1), 413, "payload_too_large"),
+            (UploadConfig { max_request_size: 1000, ..default.clone() }, multipart(), 413, "payload_too_large"),
+            // invalid items count towards request size too
+            (UploadConfig { max_request_size: 1000, ..default.clone() }, invalid_items(), 413, "payload_too_large"),
+            (UploadConfig { max_files: 1, ..default.clone() }, base64_request(2), 413, "payload_too_large"),
+            (UploadConfig { max_dimension: 100, ..default.clone() }, base64_request(1), 422, "image_too_large"),
+            (UploadConfig { max_pixels: 10000, ..default }, multipart(), 422, "image_too_large")
+        ];
+
+        for (config, request, status, expected_code) in cases {
+            let mut app = test::init_service(
+                App::new()
+                    .data(Arc::new(MemoryStorage::default()))
+                    .service(upload::bind::<MemoryStorage>("/images/upload", config, RemoteConfig::default(), import_queue(RemoteConfig::default())))
+            );
+
+            let response = test::call_service(&mut app, request);
+            assert_eq!(response.status(), status);
+
+            let body = test::read_body(response);
+            match serde_json::from_slice::<Response>(&body).unwrap() {
+                Response::Error { code, .. } => assert_eq!(code, expected_code),
+                other => panic!("Unexpected response: {:?}", other)
+            }
+        }
+
+        // multipart uploads within limits still work
+        let mut app = test::init_service(
+            App::new()
+                .data(Arc::new(MemoryStorage::default()))
+                .service(upload_service("/images/upload"))
+        );
+        let response: Response = test::read_response_json(&mut app, multipart());
+        assert_eq!(uploaded_ids(response).len(), 1);
+    }
+
+    #[test]
+    fn partial_upload() {
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+        );
+
+        let items: &[&[u8]] = &[TEST_IMAGE, b"not an image", TEST_IMAGE];
+        let base64_request = |uri: &str| {
+            let images = items.iter()
+                .map(|data| Base64Image { format: None, data: base64::encode(data) })
+                .collect();
+            test::TestRequest::post()
+                .uri(uri)
+                .set_json(&Request::Base64 { images })
+                .to_request()
+        };
+
+        // whole request fails by default
+        for request in [base64_request("/images/upload"), multipart_request("/images/upload", items).to_request()] {
+            let response = test::call_service(&mut app, request);
+            assert_eq!(response.status(), 415);
+        }
+        assert_eq!(storage.blob_count(), 0);
+
+        let requests = vec![
+            base64_request("/images/upload?partial=true"),
+            multipart_request("/images/upload?partial=true", items).to_request()
+        ];
+        for request in requests {
+            let results = match test::read_response_json(&mut app, request) {
+                Response::Results(results) => results,
+                other => panic!("Unexpected response: {:?}", other)
+            };
+
+            assert_eq!(results.len(), 3);
+            match &results[1] {
+                UploadResult::Error { code, .. } => assert_eq!(code, "unsupported_format"),
+                other => panic!("Unexpected result: {:?}", other)
+            }
+            for result in &[&results[0], &results[2]] {
+                match result {
+                    UploadResult::Ok(uploaded) => assert!(storage.load(&uploaded.id).is_ok()),
+                    other => panic!("Unexpected result: {:?}", other)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn remote_fetching() {
+        let remote = RemoteConfig {
+            allow_private: true,
+            max_redirects: 2,
+            user_agent: "test-agent".to_string(),
+            ..RemoteConfig::default()
+        };
+        let config = UploadConfig { max_file_size: 50000, ..UploadConfig::default() };
+        let mut app = test::init_service(
+            App::new()
+                .data(Arc::new(MemoryStorage::default()))
+                .service(upload::bind::<MemoryStorage>("/upload", config, remote.clone(), import_queue(remote)))
+        );
+
+        let _image = mockito::mock("GET", "/fetch/image")
+            .match_header("User-Agent", "test-agent")
+            .with_status(200)
+            .with_body(TEST_IMAGE)
+            .create();
+        let _redirect = mockito::mock("GET", "/fetch/redirect")
+            .with_status(302)
+            .with_header("Location", "image")
+            .create();
+        let _redirect_loop = mockito::mock("GET", "/fetch/loop")
+            .with_status(301)
+            .with_header("Location", "/fetch/loop")
+            .create();
+        let _large = mockito::mock("GET", "/fetch/large")
+            .with_status(200)
+            .with_body(vec![0u8; 60000])
+            .create();
+        let _missing = mockito::mock("GET", "/fetch/missing")
+            .with_status(404)
+            .create();
+
+        let paths = ["redirect", "loop", "large", "missing", "image"];
+        let request = test::TestRequest::post()
+            .uri("/upload?partial=true")
+            .set_json(&Request::Remote {
+                locations: paths.iter().map(|path| format!("{}/fetch/{}", mockito::server_url(), path)).collect()
+            })
+            .to_request();
+
+        let results = match test::read_response_json(&mut app, request) {
+            Response::Results(results) => results,
+            other => panic!("Unexpected response: {:?}", other)
+        };
+
+        let codes: Vec<_> = results.iter()
+            .map(|result| match result {
+                UploadResult::Ok(_) => "ok".to_string(),
+                UploadResult::Error { code, .. } => code.clone()
+            })
+            .collect();
+        assert_eq!(codes, ["ok", "remote_fetch_failed", "payload_too_large", "remote_fetch_failed", "ok"]);
+    }
+
+    /// needs network access, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn remote_https() {
+        let mut app = test::init_service(
+            App::new()
+                .data(Arc::new(MemoryStorage::default()))
+                .service(upload_service("/upload"))
+        );
+
+        // certificate is checked for host, although connection is made to checked address
+        let request = test::TestRequest::post()
+            .uri("/upload")
+            .set_json(&Request::Remote {
+                locations: vec!["https://www.rust-lang.org/logos/rust-logo-128x128.png".to_string()]
+            })
+            .to_request();
+        let response: Response = test::read_response_json(&mut app, request);
+        assert_eq!(uploaded_ids(response).len(), 1);
+    }
+
+    #[test]
+    fn async_upload() {
+        let remote = RemoteConfig { allow_private: true, ..RemoteConfig::default() };
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload::bind::<MemoryStorage>("/upload", UploadConfig::default(), remote.clone(), import_queue(remote)))
+                .service(jobs::bind::<MemoryStorage>("/jobs"))
+        );
+
+        let _image = mockito::mock("GET", "/job/image")
+            .with_status(200)
+            .with_body(TEST_IMAGE)
+            .create();
+        let _missing = mockito::mock("GET", "/job/missing")
+            .with_status(404)
+            .create();
+
+        let request = test::TestRequest::post()
+            .uri("/upload?async=true")
+            .set_json(&Request::Remote {
+                locations: vec![
+                    format!("{}/job/image", mockito::server_url()),
+                    format!("{}/job/missing", mockito::server_url())
+                ]
+            })
+            .to_request();
+        let response = test::call_service(&mut app, request);
+        assert_eq!(response.status(), 202);
+        let body = test::read_body(response);
+        let id = match serde_json::from_slice::<Response>(&body).unwrap() {
+            Response::Job(JobInfo { status, job }) => {
+                assert_eq!(status, JobStatus::Queued);
+                job.id
+            },
+            other => panic!("Unexpected response: {:?}", other)
+        };
+
+        // job is processed in background, runtime is driven while waiting
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let job = loop {
+            let request = test::TestRequest::get().uri(&format!("/jobs/{}", id)).to_request();
+            match test::read_response_json(&mut app, request) {
+                Response::Job(info) if info.status.is_finished() => break info,
+                Response::Job(_) => assert!(Instant::now() < deadline, "Job is not finished"),
+                other => panic!("Unexpected response: {:?}", other)
+            }
+            test::block_on(tokio_timer::Delay::new(Instant::now() + Duration::from_millis(20))).unwrap();
+        };
+
+        assert_eq!(job.status, JobStatus::Failed);
+        let items = &job.job.items;
+        assert_eq!(items[0].status, JobStatus::Succeeded);
+        assert!(storage.load(&items[0].image.as_ref().unwrap().id).is_ok());
+        assert_eq!(items[1].status, JobStatus::Failed);
+        assert_eq!(items[1].error.as_ref().unwrap().code, "remote_fetch_failed");
+
+        let request = test::TestRequest::get().uri("/jobs/unknown").to_request();
+        assert_eq!(test::call_service(&mut app, request).status(), 404);
+
+        // base64 images are always uploaded synchronously
+        let request = test::TestRequest::post()
+            .uri("/upload?async=true")
+            .set_json(&Request::Base64 { images: Vec::new() })
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).status(), 400);
+    }
+
+    #[test]
+    fn image_variants() {
+        let config = UploadConfig::default();
+        let storage = Arc::new(MemoryStorage::default());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(info::bind::<MemoryStorage>("/images"))
+                .service(variant::bind::<MemoryStorage>("/images", config.variants.clone()))
+        );
+
+        let request = multipart_request("/images/upload", &[TEST_IMAGE]).to_request();
+        let id = uploaded_ids(test::read_response_json(&mut app, request)).remove(0);
+
+        // variants are generated on upload
+        for variant in &config.variants {
+            assert!(storage.load_variant(&id, &variant.name).unwrap().is_some());
+        }
+
+        let mut get = |uri: String| {
+            let response = test::call_service(&mut app, test::TestRequest::get().uri(&uri).to_request());
+            assert_eq!(response.status(), 200, "{}", uri);
+            let content_type = response.headers().get("Content-Type").unwrap().to_str().unwrap().to_string();
+            (content_type, test::read_body(response))
+        };
+
+        let (content_type, body) = get(format!("/images/{}/thumb", id));
+        assert_eq!(content_type, "image/png");
+        assert_eq!(image::load_from_memory(&body).unwrap().dimensions(), (100, 100));
+
+        let (content_type, _) = get(format!("/images/{}/small?format=jpeg", id));
+        assert_eq!(content_type, "image/jpeg");
+
+        // other routes are not shadowed by variants
+        get(format!("/images/{}/preview", id));
+        get(format!("/images/{}/info", id));
+
+        let request = test::TestRequest::get().uri(&format!("/images/{}/huge", id)).to_request();
+        assert_eq!(test::call_service(&mut app, request).status(), 404);
+
+        // variants which weren't stored are generated from original
+        let (storage, id) = storage_with_image(test_upload());
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(upload_service("/images/upload"))
+                .service(variant::bind::<MemoryStorage>("/images", config.variants.clone()))
+        );
+        assert!(storage.load_variant(&id, "thumb").unwrap().is_none());
+
+        // they are not generated on upload of data which is already stored
+        let request = multipart_request("/images/upload", &[TEST_IMAGE]).to_request();
+        let duplicate = uploaded_ids(test::read_response_json(&mut app, request)).remove(0);
+        assert!(storage.load_variant(&duplicate, "thumb").unwrap().is_none());
+        let request = test::TestRequest::get().uri(&format!("/images/{}/thumb", id)).to_request();
+        let body = test::read_body(test::call_service(&mut app, request));
+        assert_eq!(image::load_from_memory(&body).unwrap().dimensions(), (100, 100));
+        // and stored, so they are generated only once
+        assert_eq!(storage.load_variant(&id, "thumb").unwrap().unwrap().data(), &body[..]);
+    }
+
+    #[test]
+    fn preview_cache() {
+        let storage = Arc::new(MemoryStorage::default());
+        let uploaded = storage.store(vec![test_upload()]).unwrap().remove(0);
+        let id = uploaded.id.clone();
+        let cache = test_cache();
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), cache.clone()))
+                .service(download::bind::<MemoryStorage>("/images", cache.clone()))
+                .service(stats::bind("/stats", cache.clone()))
+        );
+
+        let preview = |app: &mut _| {
+            let request = test::TestRequest::get()
+                .uri(&format!("/images/{}/preview?size=64&format=png", id))
+                .to_request();
+            test::call_service(app, request)
+        };
+
+        let first = test::read_body(preview(&mut app));
+        let second = test::read_body(preview(&mut app));
+        assert_eq!(first, second);
+
+        let request = test::TestRequest::get().uri("/stats/cache").to_request();
+        let stats = match test::read_response_json(&mut app, request) {
+            Response::CacheStats(stats) => stats,
+            other => panic!("Unexpected response: {:?}", other)
+        };
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.tiers[0].entries, 1);
+        assert_eq!(stats.tiers[0].size, first.len() as u64);
+
+        // previews of deleted image are not served from cache
+        let request = test::TestRequest::with_uri(&format!("/images/{}", id))
+            .method(Method::DELETE)
+            .header("X-Delete-Token", uploaded.delete_token.as_str())
+            .to_request();
+        assert_eq!(test::call_service(&mut app, request).status(), 204);
+        assert_eq!(cache.stats().tiers[0].entries, 0);
+        assert_eq!(preview(&mut app).status(), 404);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let upload = test_upload();
+        let hash = upload.metadata.hash.clone();
+        let (storage, id) = storage_with_image(upload);
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(preview::bind::<MemoryStorage>("/images", PreviewConfig::default(), test_cache()))
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let mut get = |uri: String, headers: &[(&str, &str)]| {
+            let request = headers.iter()
+                .fold(test::TestRequest::get().uri(&uri), |request, (name, value)| request.header(*name, *value))
+                .to_request();
+            let response = test::call_service(&mut app, request);
+            let header = |name| response.headers().get(name).map(|value| value.to_str().unwrap().to_string());
+            let (etag, last_modified) = (header("ETag").unwrap(), header("Last-Modified").unwrap());
+            assert_eq!(header("Cache-Control").unwrap(), "public, max-age=31536000, immutable");
+            (response.status(), etag, last_modified, test::read_body(response))
+        };
+
+        // tag of original is hash of its data
+        let (status, etag, last_modified, body) = get(format!("/images/{}", id), &[]);
+        assert_eq!(status, 200);
+        assert_eq!(etag, format!("\"{}\"", hash));
+        assert_eq!(&body[..], TEST_IMAGE);
+
+        let (status, _, _, body) = get(format!("/images/{}", id), &[("If-None-Match", etag.as_str())]);
+        assert_eq!(status, 304);
+        assert!(body.is_empty());
+        let (status, ..) = get(format!("/images/{}", id), &[("If-None-Match", "\"other\", W/\"more\"")]);
+        assert_eq!(status, 200);
+        let (status, ..) = get(format!("/images/{}", id), &[("If-Modified-Since", last_modified.as_str())]);
+        assert_eq!(status, 304);
+        let (status, ..) = get(format!("/images/{}", id), &[("If-Modified-Since", "Mon, 01 Jan 2001 00:00:00 GMT")]);
+        assert_eq!(status, 200);
+        // If-Modified-Since is ignored together with If-None-Match
+        let headers = [("If-None-Match", "\"other\""), ("If-Modified-Since", last_modified.as_str())];
+        let (status, ..) = get(format!("/images/{}", id), &headers);
+        assert_eq!(status, 200);
+
+        // derived images have their own tags
+        let (_, converted, ..) = get(format!("/images/{}.jpeg", id), &[]);
+        let (_, preview, ..) = get(format!("/images/{}/preview?size=32", id), &[]);
+        let (_, other_preview, ..) = get(format!("/images/{}/preview?size=64", id), &[]);
+        assert_ne!(converted, etag);
+        assert_ne!(preview, etag);
+        assert_ne!(preview, other_preview);
+
+        let (status, ..) = get(format!("/images/{}/preview?size=32", id), &[("If-None-Match", preview.as_str())]);
+        assert_eq!(status, 304);
+        let (status, ..) = get(format!("/images/{}/preview?size=64", id), &[("If-None-Match", preview.as_str())]);
+        assert_eq!(status, 200);
+
+        // images without hash don't share tag
+        let mut legacy = test_upload();
+        legacy.metadata.hash = String::new();
+        let legacy = storage.store(vec![legacy.clone(), legacy]).unwrap();
+        let (_, first, ..) = get(format!("/images/{}", legacy[0].id), &[]);
+        let (_, second, ..) = get(format!("/images/{}", legacy[1].id), &[]);
+        assert_ne!(first, "\"\"");
+        assert_ne!(first, second);
+        let (status, ..) = get(format!("/images/{}", legacy[1].id), &[("If-None-Match", first.as_str())]);
+        assert_eq!(status, 200);
+    }
+
+    #[test]
+    fn range_requests() {
+        let upload = test_upload();
+        let etag = format!("\"{}\"", upload.metadata.hash);
+        let (storage, id) = storage_with_image(upload);
+        let mut app = test::init_service(
+            App::new()
+                .data(storage.clone())
+                .service(download::bind::<MemoryStorage>("/images", test_cache()))
+        );
+
+        let size = TEST_IMAGE.len();
+        let mut request = |method: Method, uri: String, headers: &[(&str, &str)]| {
+            let request = headers.iter()
+                .fold(test::TestRequest::with_uri(&uri).method(method), |request, (name, value)| request.header(*name, *value))
+                .to_request();
+            let response = test::call_service(&mut app, request);
+            let header = |name| response.headers().get(name).map(|value| value.to_str().unwrap().to_string());
+            let headers = (header("Content-Type"), header("Content-Length"), header("Content-Range"), header("Accept-Ranges"));
+            (response.status(), headers, test::read_body(response))
+        };
+
+        // HEAD has headers of full response without body
+        let (status, (content_type, length, _, accept_ranges), body) = request(Method::HEAD, format!("/images/{}", id), &[]);
+        assert_eq!(status, 200);
+        assert_eq!(content_type.unwrap(), "image/png");
+        assert_eq!(length.unwrap(), size.to_string());
+        assert_eq!(accept_ranges.unwrap(), "bytes");
+        assert!(body.is_empty());
+        let (status, (content_type, ..), body) = request(Method::HEAD, format!("/images/{}.jpeg", id), &[]);
+        assert_eq!(status, 200);
+        assert_eq!(content_type.unwrap(), "image/jpeg");
+        assert!(body.is_empty());
+
+        let (status, (_, length, content_range, _), body) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=10-19")]);
+        assert_eq!(status, 206);
+        assert_eq!(length.unwrap(), "10");
+        assert_eq!(content_range.unwrap(), format!("bytes 10-19/{}", size));
+        assert_eq!(&body[..], &TEST_IMAGE[10..20]);
+
+        let (status, (_, _, content_range, _), body) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=-100")]);
+        assert_eq!(status, 206);
+        assert_eq!(content_range.unwrap(), format!("bytes {}-{}/{}", size - 100, size - 1, size));
+        assert_eq!(&body[..], &TEST_IMAGE[size - 100..]);
+
+        let (status, (content_type, length, ..), body) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=0-3,100-")]);
+        assert_eq!(status, 206);
+        let content_type = content_type.unwrap();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        assert_eq!(length.unwrap(), body.len().to_string());
+        let boundary = &content_type["multipart/byteranges; boundary=".len()..];
+        let mut expected = format!("\r\n--{}\r\nContent-Type: image/png\r\nContent-Range: bytes 0-3/{}\r\n\r\n", boundary, size).into_bytes();
+        expected.extend_from_slice(&TEST_IMAGE[0..4]);
+        expected.extend(format!("\r\n--{}\r\nContent-Type: image/png\r\nContent-Range: bytes 100-{}/{}\r\n\r\n", boundary, size - 1, size).bytes());
+        expected.extend_from_slice(&TEST_IMAGE[100..]);
+        expected.extend(format!("\r\n--{}--\r\n", boundary).bytes());
+        assert_eq!(&body[..], &expected[..]);
+
+        let (status, (_, _, content_range, _), _) = request(Method::GET, format!("/images/{}", id), &[("Range", format!("bytes={}-", size).as_str())]);
+        assert_eq!(status, 416);
+        assert_eq!(content_range.unwrap(), format!("bytes */{}", size));
+
+        // whole image is sent if If-Range doesn't match, invalid ranges are ignored
+        let (status, _, body) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=0-3"), ("If-Range", "\"other\"")]);
+        assert_eq!(status, 200);
+        assert_eq!(&body[..], TEST_IMAGE);
+        let (status, ..) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=0-3"), ("If-Range", etag.as_str())]);
+        assert_eq!(status, 206);
+        let (status, _, body) = request(Method::GET, format!("/images/{}", id), &[("Range", "bytes=3-0")]);
+        assert_eq!(status, 200);
+        assert_eq!(&body[..], TEST_IMAGE);
+
+        // ranges don't apply to converted images
+        let (status, (_, _, content_range, accept_ranges), _) = request(Method::GET, format!("/images/{}.jpeg", id), &[("Range", "bytes=0-3")]);
+        assert_eq!(status, 200);
+        assert!(content_range.is_none());
+        assert!(accept_ranges.is_none());
+
+        // size of legacy images is unknown, so they are always sent whole
+        let mut legacy = test_upload();
+        legacy.metadata.size = 0;
+        let legacy_id = storage.store(vec![legacy]).unwrap().remove(0).id;
+        let (status, (_, length, content_range, _), body) = request(Method::GET, format!("/images/{}", legacy_id), &[("Range", "bytes=0-3")]);
+        assert_eq!(status, 200);
+        assert_eq!(length.unwrap(), size.to_string());
+        assert!(content_range.is_none());
+        assert_eq!(&body[..], TEST_IMAGE);
+    }
+}
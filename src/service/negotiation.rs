@@ -0,0 +1,106 @@
+use actix_web::HttpRequest;
+use actix_web::http::header;
+use failure::Error;
+
+use crate::models::ImageFormat;
+
+
+/// Output format requested by client, either explicitly
+/// (query string, extension) or via Accept header.
+pub struct OutputFormat {
+    requested: Option<ImageFormat>,
+    /// (format, quality) pairs from Accept header, None stands for wildcard
+    accepted: Vec<(Option<ImageFormat>, f32)>
+}
+
+impl OutputFormat {
+    pub fn from_request(request: &HttpRequest, requested: Option<&str>) -> Result<Self, Error> {
+        let requested = match requested {
+            Some(format) => Some(format.parse::<ImageFormat>()?),
+            None => None
+        };
+
+        Ok(OutputFormat::with_format(request, requested))
+    }
+
+    /// same as from_request for already parsed format
+    pub fn with_format(request: &HttpRequest, requested: Option<ImageFormat>) -> Self {
+        let accepted = request.headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map(parse_accept)
+            .unwrap_or_default();
+
+        OutputFormat {
+            requested,
+            accepted
+        }
+    }
+
+    /// whether response depends on Accept header
+    pub fn is_negotiated(&self) -> bool {
+        self.requested.is_none()
+    }
+
+    /// choose format for image stored in `source` format,
+    /// conversion only happens if client prefers another format
+    pub fn choose(&self, source: ImageFormat) -> ImageFormat {
+        if let Some(format) = self.requested {
+            return format;
+        }
+
+        let quality_of = |format: Option<ImageFormat>| self.accepted.iter()
+            .filter(|(accepted, _)| accepted.is_none() || *accepted == format)
+            .map(|(_, quality)| *quality)
+            .fold(0.0, f32::max);
+
+        let source_quality = quality_of(Some(source));
+        let preferred = self.accepted.iter()
+            .filter_map(|(format, quality)| format.map(|format| (format, *quality)))
+            .filter(|(format, _)| format.can_encode())
+            .fold(None, |best: Option<(ImageFormat, f32)>, (format, quality)| match best {
+                Some((_, best_quality)) if best_quality >= quality => best,
+                _ => Some((format, quality))
+            });
+
+        match preferred {
+            Some((format, quality)) if quality > source_quality => format,
+            _ => source
+        }
+    }
+
+    /// choose format for derived image (e.g. preview), which is always encoded,
+    /// so PNG is used instead of source format if images can't be encoded in it
+    pub fn choose_encodable(&self, source: ImageFormat) -> ImageFormat {
+        let format = self.choose(source);
+        if self.requested.is_none() && !format.can_encode() {
+            ImageFormat::PNG
+        } else {
+            format
+        }
+    }
+}
+
+/// parse Accept header, keeping only known image types and wildcards
+fn parse_accept(accept: &str) -> Vec<(Option<ImageFormat>, f32)> {
+    accept.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let media_type = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|param| {
+                    let param = param.trim();
+                    param.strip_prefix("q=").and_then(|quality| quality.parse::<f32>().ok())
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            let format = match media_type {
+                "*/*" | "image/*" => None,
+                other => Some(ImageFormat::from_content_type(other).ok()?)
+            };
+
+            Some((format, quality))
+        })
+        .collect()
+}
@@ -1,42 +1,191 @@
 use std::sync::Arc;
 
 use actix_web::dev::HttpServiceFactory;
-use actix_web::{web, HttpResponse};
-use futures::Future;
-use failure::{Error, format_err};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header;
+use futures::future::{self, Future, Either};
+use failure::{Error, bail};
+use serde::Deserialize;
 
+use crate::cache::{ImageCache, CacheKey};
+use crate::config::{self, PreviewConfig};
+use crate::error::ApiError;
 use crate::storage::Storage;
-use crate::models::ImageId;
+use crate::models::{ImageId, Image, ResizeMode, Color};
+use super::negotiation::OutputFormat;
+use super::conditional::{Conditions, Validators};
 
 
+#[derive(Deserialize)]
+struct PreviewQuery {
+    w: Option<u32>,
+    h: Option<u32>,
+    size: Option<String>,
+    mode: Option<String>,
+    bg: Option<String>,
+    format: Option<String>
+}
+
+/// requested width and height of preview, side which is missing is unconstrained
+type RequestedSize = (Option<u32>, Option<u32>);
+
+/// box for requested size, missing side is computed from aspect ratio of image
+fn preview_box(size: RequestedSize, (image_width, image_height): (u32, u32), config: &PreviewConfig) -> (u32, u32) {
+    let scale = |side: u32, numerator: u32, denominator: u32| {
+        if denominator == 0 {
+            return config.max_size;
+        }
+        let scaled = (u64::from(side) * u64::from(numerator) + u64::from(denominator) / 2) / u64::from(denominator);
+        scaled.clamp(1, u64::from(config.max_size)) as u32
+    };
+
+    match size {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, scale(width, image_height, image_width)),
+        (None, Some(height)) => (scale(height, image_width, image_height), height),
+        (None, None) => config.default_size
+    }
+}
+
+impl PreviewQuery {
+    /// get requested preview size and check it against configured bounds,
+    /// with only `w` or `h` given, the other side is not limited
+    fn resolve_size(&self, config: &PreviewConfig) -> Result<RequestedSize, Error> {
+        let size = match (&self.size, self.w, self.h) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+                bail!("size can't be used together with w and h")
+            },
+            (Some(size), None, None) => {
+                let (width, height) = config::parse_size(size)?;
+                (Some(width), Some(height))
+            },
+            (None, w, h) => (w, h)
+        };
+
+        let in_bounds = |v: Option<u32>| v.is_none_or(|v| config.min_size <= v && v <= config.max_size);
+        if !in_bounds(size.0) || !in_bounds(size.1) {
+            bail!("Preview size should be in range [{}, {}]", config.min_size, config.max_size);
+        }
+
+        // lone side has to match the same side of some allowed size
+        let allowed = |&(width, height): &(u32, u32)| {
+            size.0.is_none_or(|v| v == width) && size.1.is_none_or(|v| v == height)
+        };
+        if size != (None, None) && !config.allowed_sizes.is_empty() && !config.allowed_sizes.iter().any(allowed) {
+            let side = |v: Option<u32>| v.map_or("*".to_string(), |v| v.to_string());
+            bail!("Preview size {}x{} is not allowed", side(size.0), side(size.1));
+        }
+
+        Ok(size)
+    }
+
+    fn resolve_mode(&self) -> Result<ResizeMode, Error> {
+        let background = match &self.bg {
+            Some(bg) => Some(bg.parse::<Color>()?),
+            None => None
+        };
+
+        match &self.mode {
+            Some(mode) => ResizeMode::parse(mode, background),
+            None => ResizeMode::parse("fit", background)
+        }
+    }
+}
+
+/// Preview is taken from cache if possible, only metadata of image is loaded in that case,
+/// because output format depends on format of image. Nothing is loaded if client already has preview.
+fn cached_preview<S>(
+    storage: &S,
+    cache: &ImageCache,
+    id: &ImageId,
+    (size, mode, output): (RequestedSize, ResizeMode, OutputFormat),
+    config: &PreviewConfig,
+    conditions: &Conditions
+) -> Result<(Validators, Option<Image>), Error>
+    where S: Storage
+{
+    let metadata = storage.metadata(id)?;
+    let size = preview_box(size, (metadata.width, metadata.height), config);
+    let format = output.choose_encodable(metadata.format);
+    let key = CacheKey::preview(id, size, mode, format);
+    let validators = Validators::new(id, &metadata, Some(&key.params));
+    if conditions.is_fresh(&validators) {
+        return Ok((validators, None));
+    }
+    if let Some(preview) = cache.get(&key) {
+        return Ok((validators, Some(preview)));
+    }
+
+    let preview = storage.load(id)?.preview(size, mode, format)?;
+    cache.put(&key, &preview);
+    Ok((validators, Some(preview)))
+}
+
 fn generate_preview<S>(
-    state: web::Data<Arc<S>>, 
-    info: web::Path<(u32,)>
-) -> impl Future<Item=HttpResponse, Error=Error>
+    state: web::Data<Arc<S>>,
+    cache: web::Data<Arc<ImageCache>>,
+    config: web::Data<PreviewConfig>,
+    info: web::Path<(String,)>,
+    query: web::Query<PreviewQuery>,
+    request: HttpRequest
+) -> impl Future<Item=HttpResponse, Error=ApiError>
     where S: Storage
 {
+    let id = match info.0.parse::<ImageId>() {
+        Ok(id) => id,
+        Err(e) => return Either::B(future::err(e.into()))
+    };
+
+    let params = query.resolve_size(config.get_ref())
+        .and_then(|size| query.resolve_mode().map(|mode| (size, mode)))
+        .map_err(|e| ApiError::BadRequest(format!("{}", e)))
+        .and_then(|(size, mode)| {
+            OutputFormat::from_request(&request, query.format.as_deref())
+                .map(|output| (size, mode, output))
+                .map_err(ApiError::from)
+        });
+
+    let params = match params {
+        Ok(params) => params,
+        Err(e) => return Either::B(future::err(e))
+    };
+
+    let negotiated = params.2.is_negotiated();
+    let conditions = Conditions::from_request(&request);
     let storage = state.get_ref().clone();
-    let id = ImageId(info.0 as i32);
-    web::block(move || {
-        storage.load(id)
-            .map_err(|e| format_err!("Failed to load image: {}", e))
-    })
-    .map_err(|e| format_err!("{}", e))
-    .map(|image| image.preview((100, 100)))
-    .and_then(|preview| preview.data().map(|data| (preview.format(), data)))
-    .map(|(format, data)|
-         HttpResponse::Ok()
-            .content_type(format!("image/{}", format))
-            .content_length(data.len() as u64)
-            .body(data)
+    let cache = cache.get_ref().clone();
+    Either::A(
+        web::block(move || cached_preview(storage.as_ref(), &cache, &id, params, config.get_ref(), &conditions))
+        .map_err(|e| ApiError::from(e).context("Failed to generate preview"))
+        .map(move |(validators, preview)| {
+            let mut response = match preview {
+                Some(_) => HttpResponse::Ok(),
+                None => HttpResponse::NotModified()
+            };
+            validators.apply(&mut response);
+            if negotiated {
+                response.header(header::VARY, "Accept");
+            }
+
+            let preview = match preview {
+                Some(preview) => preview,
+                None => return response.finish()
+            };
+            response
+                .content_type(preview.format().content_type())
+                .content_length(preview.data().len() as u64)
+                .body(preview.into_data())
+        })
     )
 }
 
-pub fn bind<S>(prefix: &str) -> impl HttpServiceFactory
+pub fn bind<S>(prefix: &str, config: PreviewConfig, cache: Arc<ImageCache>) -> impl HttpServiceFactory
     where S: Storage
 {
     let path = prefix.to_string() + "/{id}/preview";
     web::resource(&path)
+        .data(config)
+        .data(cache)
         .route(web::get().to_async(generate_preview::<S>))
-    
+
 }
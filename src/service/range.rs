@@ -0,0 +1,230 @@
+//! Byte ranges of original images. Data is streamed from storage in chunks,
+//! so neither full nor partial responses keep whole image in memory.
+
+use std::cmp;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::Arc;
+
+use actix_web::{web, HttpResponse};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::{header, StatusCode};
+use actix_web::web::Bytes;
+use failure::{Error, format_err};
+use futures::{stream, Future, Stream};
+
+use crate::error::ApiError;
+use crate::models::{ImageId, ImageMetadata};
+use crate::storage::Storage;
+
+/// size of single read from reader of range
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// requests with more ranges get whole image
+const MAX_RANGES: usize = 16;
+
+/// Ranges requested by client
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// Range header is missing, invalid or ignored
+    Full,
+    /// satisfiable ranges in request order
+    Partial(Vec<Range<u64>>),
+    /// none of ranges overlaps data
+    Unsatisfiable
+}
+
+/// Ok(None) if range is valid, but it is outside of data
+fn parse_range(spec: &str, size: u64) -> Result<Option<Range<u64>>, ()> {
+    let dash = spec.find('-').ok_or(())?;
+    let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+    let number = |value: &str| value.parse::<u64>().map_err(|_| ());
+
+    match (first.is_empty(), last.is_empty()) {
+        // suffix, e.g. "-500" is last 500 bytes
+        (true, false) => {
+            let length = number(last)?;
+            Ok(Some(size.saturating_sub(length)..size).filter(|range| range.start < range.end))
+        },
+        (false, true) => {
+            let start = number(first)?;
+            Ok(Some(start..size).filter(|range| range.start < range.end))
+        },
+        (false, false) => {
+            let (start, end) = (number(first)?, number(last)?);
+            if end < start {
+                return Err(());
+            }
+            Ok(Some(start..cmp::min(end + 1, size)).filter(|range| range.start < range.end))
+        },
+        (true, true) => Err(())
+    }
+}
+
+impl Ranges {
+    /// Parse value of Range header for data of given size, only bytes unit is supported
+    pub fn parse(header: Option<&str>, size: u64) -> Ranges {
+        let specs = match header.map(str::trim) {
+            Some(header) if header.starts_with("bytes=") => &header["bytes=".len()..],
+            _ => return Ranges::Full
+        };
+
+        let ranges: Result<Vec<_>, ()> = specs.split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .map(|spec| parse_range(spec, size))
+            .collect();
+
+        match ranges {
+            Ok(ref ranges) if ranges.is_empty() || ranges.len() > MAX_RANGES => Ranges::Full,
+            Ok(ranges) => {
+                let ranges: Vec<_> = ranges.into_iter().flatten().collect();
+                if ranges.is_empty() {
+                    Ranges::Unsatisfiable
+                } else {
+                    Ranges::Partial(ranges)
+                }
+            },
+            Err(_) => Ranges::Full
+        }
+    }
+}
+
+/// next chunk of range, reader is returned with it, so it can be used for the next one
+fn read_chunk(mut reader: Box<dyn Read + Send>, remaining: u64) -> Result<(Bytes, Box<dyn Read + Send>), Error> {
+    let mut chunk = vec![0; cmp::min(remaining, CHUNK_SIZE) as usize];
+    reader.read_exact(&mut chunk).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => format_err!("Data of image is shorter than its size"),
+        _ => e.into()
+    })?;
+    Ok((Bytes::from(chunk), reader))
+}
+
+/// Range is read chunk by chunk from single reader, so image is looked up only once.
+/// Stream fails if reader ends before range (e.g. image was deleted), so connection is closed
+/// instead of sending body shorter than its Content-Length.
+fn read_range<S>(storage: Arc<S>, id: ImageId, range: Range<u64>) -> impl Stream<Item=Bytes, Error=ApiError>
+    where S: Storage
+{
+    let length = range.end - range.start;
+    web::block(move || storage.read_range(&id, range))
+        .map_err(|e| ApiError::from(e).context("Failed to load image"))
+        .map(move |reader| {
+            stream::unfold((reader, length), |(reader, remaining)| {
+                if remaining == 0 {
+                    return None;
+                }
+                let chunk = web::block(move || read_chunk(reader, remaining))
+                    .map(move |(chunk, reader)| {
+                        let remaining = remaining - chunk.len() as u64;
+                        (chunk, (reader, remaining))
+                    })
+                    .map_err(|e| ApiError::from(e).context("Failed to read image"));
+                Some(chunk)
+            })
+        })
+        .flatten_stream()
+}
+
+/// Response with original data of image, body is empty for HEAD requests.
+/// Single range is sent as is, multiple ranges as multipart/byteranges.
+pub fn respond<S>(
+    mut response: HttpResponseBuilder,
+    storage: Arc<S>,
+    id: ImageId,
+    metadata: &ImageMetadata,
+    ranges: Ranges,
+    head: bool
+) -> HttpResponse
+    where S: Storage
+{
+    let size = metadata.size;
+    let content_type = metadata.format.content_type();
+    response.header(header::ACCEPT_RANGES, "bytes");
+
+    // each range is preceded by its part header, suffix closes multipart body
+    let (parts, suffix) = match ranges {
+        Ranges::Unsatisfiable => {
+            return response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .finish();
+        },
+        Ranges::Full => {
+            response.content_type(content_type);
+            (vec![(Bytes::new(), 0..size)], Bytes::new())
+        },
+        Ranges::Partial(ref ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .content_type(content_type)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, size));
+            (vec![(Bytes::new(), range)], Bytes::new())
+        },
+        Ranges::Partial(ranges) => {
+            let boundary = ImageId::generate();
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .content_type(format!("multipart/byteranges; boundary={}", boundary));
+
+            let parts = ranges.into_iter()
+                .map(|range| {
+                    let part = format!(
+                        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, range.start, range.end - 1, size
+                    );
+                    (Bytes::from(part), range)
+                })
+                .collect();
+            (parts, Bytes::from(format!("\r\n--{}--\r\n", boundary)))
+        }
+    };
+
+    let length = parts.iter()
+        .map(|(part, range)| part.len() as u64 + (range.end - range.start))
+        .sum::<u64>() + suffix.len() as u64;
+    response.content_length(length);
+    if head || length == 0 {
+        return response.finish();
+    }
+
+    let body = stream::iter_ok::<_, ApiError>(parts)
+        .map(move |(part, range)| stream::once(Ok(part)).chain(read_range(storage.clone(), id.clone(), range)))
+        .flatten()
+        .chain(stream::once(Ok(suffix)))
+        .filter(|chunk| !chunk.is_empty());
+
+    // length is known, so body doesn't need chunked encoding
+    response
+        .no_chunking()
+        .streaming(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ranges;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn parse() {
+        let parse = |header| Ranges::parse(Some(header), 1000);
+        assert_eq!(parse("bytes=0-99"), Ranges::Partial(vec![0..100]));
+        assert_eq!(parse("bytes=900-"), Ranges::Partial(vec![900..1000]));
+        assert_eq!(parse("bytes=-100"), Ranges::Partial(vec![900..1000]));
+        assert_eq!(parse("bytes=-2000"), Ranges::Partial(vec![0..1000]));
+        assert_eq!(parse("bytes=990-1999"), Ranges::Partial(vec![990..1000]));
+        assert_eq!(parse("bytes=0-0, 10-19 ,-1"), Ranges::Partial(vec![0..1, 10..20, 999..1000]));
+        // unsatisfiable ranges are skipped while there is satisfiable one
+        assert_eq!(parse("bytes=1000-,0-1"), Ranges::Partial(vec![0..2]));
+        assert_eq!(parse("bytes=1000-1100"), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), Ranges::Unsatisfiable);
+
+        assert_eq!(Ranges::parse(None, 1000), Ranges::Full);
+        assert_eq!(parse("items=0-1"), Ranges::Full);
+        assert_eq!(parse("bytes=5-1"), Ranges::Full);
+        assert_eq!(parse("bytes=a-b"), Ranges::Full);
+        assert_eq!(parse("bytes=-"), Ranges::Full);
+        assert_eq!(parse("bytes="), Ranges::Full);
+        assert_eq!(parse(format!("bytes={}", vec!["0-1"; 17].join(",")).as_str()), Ranges::Full);
+    }
+}
@@ -0,0 +1,213 @@
+//! Protection against server side request forgery: remote uploads can't be used
+//! to reach internal services, cloud metadata endpoints and so on.
+//!
+//! Host is resolved and checked by connector of HTTP client, which connects to the checked
+//! address, so DNS can't return another address when connection is made. TLS is established
+//! on top of this connection with original host, so SNI and certificate checks are not affected.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use actix_connect::{Connect, ConnectError, TcpConnector};
+use actix_web::web;
+use actix_web::dev::Service;
+use actix_web::error::BlockingError;
+use actix_web::http::Uri;
+use failure::Error;
+use futures::{Async, Future, Poll};
+use lazy_static::lazy_static;
+
+use crate::config::{IpNetwork, RemoteConfig};
+use crate::error::ApiError;
+
+/// private, loopback, link-local, multicast, documentation and other special networks
+const SPECIAL_NETWORKS: &[&str] = &[
+    "0.0.0.0/8", "10.0.0.0/8", "100.64.0.0/10", "127.0.0.0/8", "169.254.0.0/16", "172.16.0.0/12",
+    "192.0.0.0/24", "192.0.2.0/24", "192.88.99.0/24", "192.168.0.0/16", "198.18.0.0/15",
+    "198.51.100.0/24", "203.0.113.0/24", "224.0.0.0/4", "240.0.0.0/4",
+    "::/96", "64:ff9b::/96", "100::/64", "2001::/23", "2001:db8::/32", "2002::/16",
+    "fc00::/7", "fe80::/10", "ff00::/8"
+];
+
+lazy_static! {
+    static ref SPECIAL: Vec<IpNetwork> = SPECIAL_NETWORKS.iter()
+        .map(|network| network.parse().expect("Invalid special network"))
+        .collect();
+}
+
+fn forbidden(message: String) -> Error {
+    ApiError::Forbidden(message).into()
+}
+
+/// IPv4 addresses mapped to IPv6 are checked as IPv4
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                IpAddr::V4(((u32::from(high) << 16) | u32::from(low)).into())
+            },
+            _ => address
+        },
+        address => address
+    }
+}
+
+fn is_denied(address: IpAddr, config: &RemoteConfig) -> bool {
+    let address = canonical(address);
+    let special = !config.allow_private && SPECIAL.iter().any(|network| network.contains(address));
+
+    special || config.denied_networks.iter().any(|network| network.contains(address))
+}
+
+/// host is equal to one from list or is its subdomain
+fn matches_host(host: &str, hosts: &[String]) -> bool {
+    hosts.iter().any(|entry| host == entry || host.ends_with(&format!(".{}", entry)))
+}
+
+/// Check scheme and host, resolve host and check all its addresses, address to connect to is returned.
+/// Blocking, because host is resolved with system resolver.
+pub fn resolve(uri: &Uri, config: &RemoteConfig) -> Result<SocketAddr, Error> {
+    let scheme = uri.scheme_str().unwrap_or_default().to_ascii_lowercase();
+    if !config.schemes.contains(&scheme) {
+        return Err(forbidden(format!("Scheme of {} is not allowed", uri)));
+    }
+
+    let host = uri.host().ok_or_else(|| ApiError::BadRequest(format!("Uri {} doesn't have host", uri)))?;
+    let port = uri.port_u16().unwrap_or(if scheme == "https" { 443 } else { 80 });
+    resolve_host(host, port, config)
+}
+
+/// Check host, resolve it and check all its addresses. Blocking, like `resolve`.
+fn resolve_host(host: &str, port: u16, config: &RemoteConfig) -> Result<SocketAddr, Error> {
+    let host = Some(host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| ApiError::BadRequest("Host is empty".to_string()))?;
+
+    if !config.allowed_hosts.is_empty() && !matches_host(&host, &config.allowed_hosts) {
+        return Err(forbidden(format!("Host {} is not allowed", host)));
+    }
+    if matches_host(&host, &config.denied_hosts) {
+        return Err(forbidden(format!("Host {} is denied", host)));
+    }
+
+    let addresses: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+        Ok(address) => vec![SocketAddr::new(address, port)],
+        Err(_) => (host.as_str(), port).to_socket_addrs()
+            .map_err(|e| ApiError::RemoteFetchFailed(format!("Failed to resolve {}: {}", host, e)))?
+            .collect()
+    };
+
+    // host with any denied address is rejected, otherwise DNS could alternate between them
+    if let Some(address) = addresses.iter().find(|address| is_denied(address.ip(), config)) {
+        return Err(forbidden(format!("Address {} of host {} is not allowed", address.ip(), host)));
+    }
+    addresses.first()
+        .cloned()
+        .ok_or_else(|| ApiError::RemoteFetchFailed(format!("Host {} doesn't have addresses", host)).into())
+}
+
+/// TCP connector of HTTP client, which only connects to addresses checked by `resolve_host`,
+/// scheme is checked by `resolve` before request is sent
+#[derive(Clone)]
+pub struct GuardedConnector {
+    config: Arc<RemoteConfig>
+}
+
+impl GuardedConnector {
+    pub fn new(config: RemoteConfig) -> Self {
+        GuardedConnector {
+            config: Arc::new(config)
+        }
+    }
+}
+
+impl Service for GuardedConnector {
+    type Request = Connect<Uri>;
+    type Response = <TcpConnector<Uri> as Service>::Response;
+    type Error = ConnectError;
+    type Future = Box<dyn Future<Item=Self::Response, Error=Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
+
+    fn call(&mut self, request: Connect<Uri>) -> Self::Future {
+        let (host, port, config) = (request.host().to_string(), request.port(), self.config.clone());
+        Box::new(
+            web::block(move || resolve_host(&host, port, &config).map_err(|e| e.to_string()))
+                .map_err(|e| {
+                    let message = match e {
+                        BlockingError::Error(message) => message,
+                        BlockingError::Canceled => "Address check was canceled".to_string()
+                    };
+                    ConnectError::Io(io::Error::other(message))
+                })
+                .and_then(move |address| TcpConnector::new().call(request.set_addr(Some(address))))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::Uri;
+
+    use crate::config::{IpNetwork, RemoteConfig};
+
+    fn check(uri: &str, config: &RemoteConfig) -> bool {
+        super::resolve(&uri.parse::<Uri>().unwrap(), config).is_ok()
+    }
+
+    #[test]
+    fn special_networks_parse() {
+        for network in super::SPECIAL_NETWORKS {
+            assert!(network.parse::<IpNetwork>().is_ok(), "{} doesn't parse", network);
+        }
+        assert_eq!(super::SPECIAL.len(), super::SPECIAL_NETWORKS.len());
+    }
+
+    #[test]
+    fn special_addresses() {
+        let config = RemoteConfig::default();
+        let denied = [
+            "http://127.0.0.1/image.png", "http://localhost:8080/", "http://169.254.169.254/latest/meta-data/",
+            "http://10.1.2.3/", "http://192.168.0.1/", "http://0.0.0.0/", "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/", "http://[fe80::1]/", "http://[fd00::1]/"
+        ];
+        for uri in &denied {
+            assert!(!check(uri, &config), "{} should be denied", uri);
+        }
+
+        assert!(check("http://93.184.216.34/image.png", &config));
+        assert!(check("http://[2606:2800:220:1:248:1893:25c8:1946]/", &config));
+
+        let config = RemoteConfig { allow_private: true, ..RemoteConfig::default() };
+        assert!(check("http://127.0.0.1:1234/", &config));
+    }
+
+    #[test]
+    fn restrictions() {
+        let config = RemoteConfig {
+            schemes: vec!["http".to_string()],
+            denied_networks: vec!["93.184.216.0/24".parse().unwrap()],
+            ..RemoteConfig::default()
+        };
+        assert!(!check("ftp://1.1.1.1/image.png", &config));
+        assert!(!check("https://1.1.1.1/image.png", &config));
+        assert!(!check("http://93.184.216.34/", &config));
+        assert!(check("http://1.1.1.1/", &config));
+
+        let config = RemoteConfig {
+            allowed_hosts: vec!["1.1.1.1".to_string(), "example.com".to_string()],
+            denied_hosts: vec!["internal.example.com".to_string()],
+            ..RemoteConfig::default()
+        };
+        assert!(check("http://1.1.1.1/", &config));
+        assert!(!check("http://8.8.8.8/", &config));
+        assert!(!check("http://internal.example.com/", &config));
+        assert!(!check("http://api.internal.example.com/", &config));
+
+        let address = super::resolve(&"http://1.1.1.1:8080/a?b=c".parse::<Uri>().unwrap(), &config).unwrap();
+        assert_eq!(address, "1.1.1.1:8080".parse().unwrap());
+    }
+}
@@ -1,7 +1,9 @@
 use failure::Error;
 use serde::{Serialize, Deserialize};
 
-use crate::models::{Image, ImageFormat};
+use crate::config::UploadConfig;
+use crate::error::ApiError;
+use crate::models::{ImageFormat, ImageSource, Upload};
 
 
 #[derive(Serialize, Deserialize)]
@@ -17,14 +19,20 @@ pub enum Request {
 
 #[derive(Serialize, Deserialize)]
 pub struct Base64Image {
-    pub format: ImageFormat,
+    /// detected from data if not specified
+    pub format: Option<ImageFormat>,
     pub data: String
 }
 
 impl Base64Image {
-    pub fn decode(&self) -> Result<Image, Error> {
-        let binary_data = base64::decode(&self.data)?;
-        Image::decode(&binary_data, self.format)
+    pub fn decode(&self, limits: &UploadConfig) -> Result<Upload, Error> {
+        let binary_data = base64::decode(&self.data)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid base64 data: {}", e)))?;
+        if binary_data.len() > limits.max_file_size {
+            return Err(ApiError::PayloadTooLarge(format!("Image is larger than {} bytes", limits.max_file_size)).into());
+        }
+
+        Upload::detect(&binary_data, self.format, ImageSource::Base64, limits)
     }
 }
 
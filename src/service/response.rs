@@ -1,10 +1,72 @@
 use serde::{Serialize, Deserialize};
 
-use crate::models::ImageId;
+use crate::cache::CacheStats;
+use crate::error::ApiError;
+use crate::models::{ImageId, ImageMetadata, UploadedImage, Job, JobStatus};
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all="snake_case")]
 pub enum Response {
-    Error(String),
-    Ids(Vec<ImageId>)
+    Error {
+        code: String,
+        message: String
+    },
+    Uploaded(Vec<UploadedImage>),
+    /// result of each uploaded item in the same order as in request
+    Results(Vec<UploadResult>),
+    Info(ImageInfo),
+    Job(JobInfo),
+    CacheStats(CacheStats)
 }
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum UploadResult {
+    Ok(UploadedImage),
+    Error {
+        code: String,
+        message: String
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub id: ImageId,
+    #[serde(flatten)]
+    pub metadata: ImageMetadata
+}
+
+/// State of remote import job and of each of its items
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub status: JobStatus,
+    #[serde(flatten)]
+    pub job: Job
+}
+
+impl Response {
+    pub fn error(e: &ApiError) -> Response {
+        Response::Error {
+            code: e.code().to_string(),
+            message: format!("{}", e)
+        }
+    }
+}
+
+impl UploadResult {
+    pub fn error(e: &ApiError) -> UploadResult {
+        UploadResult::Error {
+            code: e.code().to_string(),
+            message: format!("{}", e)
+        }
+    }
+}
+
+impl JobInfo {
+    pub fn new(job: Job) -> JobInfo {
+        JobInfo {
+            status: job.status(),
+            job
+        }
+    }
+}
\ No newline at end of file
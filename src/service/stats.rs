@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{web, HttpResponse};
+
+use crate::cache::ImageCache;
+use super::Response;
+
+
+/// hit/miss counters and usage of derived image cache
+fn cache_stats(cache: web::Data<Arc<ImageCache>>) -> HttpResponse {
+    HttpResponse::Ok().json(Response::CacheStats(cache.stats()))
+}
+
+pub fn bind(prefix: &str, cache: Arc<ImageCache>) -> impl HttpServiceFactory {
+    let path = prefix.to_string() + "/cache";
+    web::resource(&path)
+        .data(cache)
+        .route(web::get().to(cache_stats))
+}
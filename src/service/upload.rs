@@ -1,19 +1,39 @@
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
-use failure::{Error, format_err, bail};
 use actix_multipart::{Multipart, Field};
 use actix_web::{web, guard, HttpResponse, FromRequest};
 use actix_web::dev::HttpServiceFactory;
-use actix_web::http::Uri;
-use actix_web::client::Client;
+use actix_web::http::{header, Uri};
+use actix_web::error::JsonPayloadError;
+use actix_web::client::{Client, Connector};
+use actix_rt::Arbiter;
 use futures::stream::{self, Stream};
-use futures::future::{self, Future, Either};
+use futures::future::{self, Future, Either, Loop};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use failure::Error;
+use log::{info, error};
+use serde::Deserialize;
+use tokio_timer::Timeout;
 
-use crate::models::{ImageFormat, Image, ImageId};
+use crate::config::{UploadConfig, RemoteConfig};
+use crate::error::ApiError;
+use crate::models::{ImageFormat, ImageSource, Upload, UploadedImage, Variant, Job, JobId, JobItem, JobStatus};
 use crate::storage::Storage;
-use super::{Request, Response, Base64Image};
+use super::{Request, Response, UploadResult, JobInfo, Base64Image};
+use super::remote;
 
 
+#[derive(Deserialize)]
+struct UploadQuery {
+    /// report result of each item instead of failing the whole request
+    #[serde(default)]
+    partial: bool,
+    /// download remote images in background job and respond with its id immediately
+    #[serde(rename = "async", default)]
+    asynchronous: bool
+}
+
 /// simple interface for testing purposes
 fn upload_form() -> HttpResponse {
     let html = 
@@ -30,152 +50,638 @@ fn upload_form() -> HttpResponse {
     HttpResponse::Ok().body(html)
 }
 
-/// extract image type
-fn extract_type(data: &Field) -> Result<ImageFormat, Error> {
-    let content_type = data.content_type();
-    let type_ = content_type.type_().as_str();
-    
-    if type_ != "image" {
-        bail!("Unsupported content type: {}", type_);
+/// image type declared by client, it is only a hint for format detection
+/// (formats without magic bytes, e.g. TGA, can't be detected without it)
+fn declared_type(data: &Field) -> Option<ImageFormat> {
+    ImageFormat::from_content_type(data.content_type().essence_str()).ok()
+}
+
+fn check_count(count: usize, config: &UploadConfig) -> Result<(), ApiError> {
+    if count > config.max_files {
+        return Err(ApiError::PayloadTooLarge(format!("Request can't contain more than {} images", config.max_files)));
+    }
+    Ok(())
+}
+
+/// limit for size of next image, when `received` bytes of request were already received
+fn size_limit(received: usize, config: &UploadConfig) -> (usize, String) {
+    let remaining = config.max_request_size.saturating_sub(received);
+    if remaining < config.max_file_size {
+        (remaining, format!("Request is larger than {} bytes", config.max_request_size))
+    } else {
+        (config.max_file_size, format!("Image is larger than {} bytes", config.max_file_size))
     }
-    
-    let subtype = content_type.subtype().as_str();
-    let format = subtype.parse::<ImageFormat>()?;
-    Ok(format)
+}
+
+/// collect stream into memory, PayloadTooLarge with given message is returned if it exceeds limit
+fn read_limited<S>(stream: S, (limit, message): (usize, String)) -> impl Future<Item=Vec<u8>, Error=ApiError>
+    where S: Stream<Error=ApiError>,
+          S::Item: AsRef<[u8]>
+{
+    stream.fold(Vec::new(), move |mut data, chunk| {
+        let chunk = chunk.as_ref();
+        if data.len() + chunk.len() > limit {
+            return Err(ApiError::PayloadTooLarge(message.clone()));
+        }
+
+        data.extend_from_slice(chunk);
+        Ok(data)
+    })
+}
+
+/// Item of request and number of bytes received for it,
+/// size of failed items is known too, so they count towards request size
+type Received = (Result<Upload, ApiError>, usize);
+
+/// decode received item, it is counted even if it isn't an image
+fn decode_item(bytes: &[u8], declared: Option<ImageFormat>, source: ImageSource, config: &UploadConfig) -> Received {
+    (Upload::detect(bytes, declared, source, config).map_err(ApiError::from), bytes.len())
+}
+
+/// item which failed while it was received, more than limit was received of oversized item
+fn failed_item(e: ApiError, limit: usize) -> Received {
+    let size = match e {
+        ApiError::PayloadTooLarge(_) => limit + 1,
+        _ => 0
+    };
+    (Err(e), size)
 }
 
 /// parse image from multipart item
-fn extract_image(data: Field) -> impl Future<Item=Image, Error=Error> {
-    match extract_type(&data) {
-        Err(e) => Either::B(future::err(e)),
-        Ok(format) => Either::A(
-            data.concat2()
-                .map_err(|e| format_err!("Multipart error: {}", e))
-                .and_then(move |bytes| Image::decode(&bytes, format))
-        )
+fn extract_image(data: Field, config: UploadConfig, received: usize) -> impl Future<Item=Received, Error=ApiError> {
+    let declared = declared_type(&data);
+    let (limit, message) = size_limit(received, &config);
+    let data = data.map_err(|e| ApiError::BadRequest(format!("Multipart error: {}", e)));
+
+    read_limited(data, (limit, message))
+        .then(move |bytes| -> Result<Received, ApiError> {
+            Ok(match bytes {
+                Ok(bytes) => decode_item(&bytes, declared, ImageSource::Multipart, &config),
+                Err(e) => failed_item(e, limit)
+            })
+        })
+}
+
+/// Request items which were received so far and number of received bytes.
+/// Failed items are only kept in partial mode.
+type Items = (Vec<Result<Upload, ApiError>>, usize);
+
+/// in partial mode failed items are reported in response, otherwise they fail the whole request
+fn item_result<T>(partial: bool, result: Result<T, ApiError>) -> Result<Result<T, ApiError>, ApiError> {
+    match result {
+        Err(e) if !partial => Err(e),
+        result => Ok(result)
+    }
+}
+
+/// Add received item to items, checking size of request.
+/// Request size is a limit of the whole request, so exceeding it fails request even in partial mode.
+fn push_item(
+    partial: bool,
+    config: &UploadConfig,
+    (mut items, received): Items,
+    (result, size): Received
+) -> Result<Items, ApiError> {
+    let received = received + size;
+    if received > config.max_request_size {
+        return Err(ApiError::PayloadTooLarge(format!("Request is larger than {} bytes", config.max_request_size)));
     }
+
+    items.push(item_result(partial, result)?);
+    Ok((items, received))
+}
+
+/// Variants are only generated for data which isn't stored yet (or which is stored
+/// by earlier item of the same request), other images share already stored variants.
+fn generate_variants<S>(
+    storage: &S,
+    items: Vec<Result<Upload, ApiError>>,
+    variants: &[Variant],
+    partial: bool
+) -> Result<Vec<Result<Upload, ApiError>>, Error>
+    where S: Storage
+{
+    let mut generated = HashSet::new();
+    items.into_iter()
+        .map(|item| {
+            let upload = match item {
+                Ok(upload) => upload,
+                Err(e) => return Ok(Err(e))
+            };
+            let hash = upload.metadata.hash.clone();
+            if variants.is_empty() || generated.contains(&hash) || storage.contains(&hash)? {
+                return Ok(Ok(upload));
+            }
+
+            match upload.with_variants(variants) {
+                Ok(upload) => {
+                    generated.insert(hash);
+                    Ok(Ok(upload))
+                },
+                Err(e) if partial => Ok(Err(ApiError::from(e))),
+                Err(e) => Err(e)
+            }
+        })
+        .collect()
 }
 
-/// save images to storage asynchronously and return their ids
-fn store_images<S>(storage: Arc<S>, images: Vec<Image>) -> impl Future<Item=Vec<ImageId>, Error=Error>
+/// Generate variants and save images to storage asynchronously, result of each item is returned in order.
+/// Variants are generated here, so resizing doesn't block event loop. In partial mode failure
+/// to generate variants fails only its item, storage failure still fails all of them.
+fn store_images<S>(
+    storage: Arc<S>,
+    items: Vec<Result<Upload, ApiError>>,
+    variants: Vec<Variant>,
+    partial: bool
+) -> impl Future<Item=Vec<Result<UploadedImage, ApiError>>, Error=ApiError>
     where S: Storage
 {
-    web::block(move || storage.store(images))
-        .map_err(|e| format_err!("{}", e))
+    web::block(move || -> Result<Vec<Result<UploadedImage, ApiError>>, Error> {
+        let items = generate_variants(storage.as_ref(), items, &variants, partial)?;
+        let mut images = Vec::with_capacity(items.len());
+        let mut errors = Vec::with_capacity(items.len());
+        for item in items {
+            match item {
+                Ok(upload) => {
+                    images.push(upload);
+                    errors.push(None);
+                },
+                Err(e) => errors.push(Some(e))
+            }
+        }
+
+        // storage returns results in order of images
+        let mut uploaded = storage.store(images)?.into_iter();
+        errors.into_iter()
+            .map(|error| match error {
+                Some(e) => Ok(Err(e)),
+                None => uploaded.next()
+                    .map(Ok)
+                    .ok_or_else(|| ApiError::Internal("Storage returned fewer results than images".to_string()).into())
+            })
+            .collect()
+    })
+    .map_err(ApiError::from)
 }
 
-/// multipart/form-data handler
-fn upload_multipart<S>(state: web::Data<Arc<S>>, stream: Multipart) -> impl Future<Item=HttpResponse, Error=Error> 
+/// Store successfully parsed items, response contains result of each item in partial mode.
+/// Storage failure still fails the whole request.
+fn store_items<S>(
+    storage: Arc<S>,
+    items: Vec<Result<Upload, ApiError>>,
+    variants: Vec<Variant>,
+    partial: bool
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    store_images(storage, items, variants, partial)
+        .and_then(move |results| {
+            if !partial {
+                // any failed item has already failed the whole request
+                let uploaded = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+                return Ok(HttpResponse::Ok().json(Response::Uploaded(uploaded)));
+            }
+
+            let results = results.into_iter()
+                .map(|result| match result {
+                    Ok(uploaded) => UploadResult::Ok(uploaded),
+                    Err(e) => UploadResult::error(&e)
+                })
+                .collect();
+            Ok(HttpResponse::Ok().json(Response::Results(results)))
+        })
+}
+
+/// multipart/form-data handler, items are received one by one to enforce limits
+fn upload_multipart<S>(
+    state: web::Data<Arc<S>>,
+    config: web::Data<UploadConfig>,
+    query: web::Query<UploadQuery>,
+    stream: Multipart
+) -> impl Future<Item=HttpResponse, Error=ApiError>
     where S: Storage 
 {
     let storage = state.get_ref().clone();
+    let config = config.get_ref().clone();
+    let variants = config.variants.clone();
+    let partial = query.partial;
     stream
-        .map_err(|e| format_err!("Multipart error: {}", e))
-        .and_then(extract_image)
-        .collect()
-        .and_then(move |images| store_images(storage, images))
-        .map(|ids| HttpResponse::Ok().json(Response::Ids(ids)))
+        .map_err(|e| ApiError::BadRequest(format!("Multipart error: {}", e)))
+        .fold((Vec::new(), 0), move |items: Items, field| {
+            if let Err(e) = check_count(items.0.len() + 1, &config) {
+                return Either::B(future::err(e));
+            }
+
+            let config = config.clone();
+            Either::A(
+                extract_image(field, config.clone(), items.1)
+                    .and_then(move |item| push_item(partial, &config, items, item))
+            )
+        })
+        .and_then(move |(items, _)| store_items(storage, items, variants, partial))
 }
 
-/// base64 images handler
-fn upload_base64<S>(storage: Arc<S>, images: Vec<Base64Image>) -> impl Future<Item=HttpResponse, Error=Error>
+/// base64 images handler, size of request is limited by JSON config
+fn upload_base64<S>(
+    storage: Arc<S>,
+    images: Vec<Base64Image>,
+    config: &UploadConfig,
+    partial: bool
+) -> impl Future<Item=HttpResponse, Error=ApiError>
     where S: Storage
 {
-    let images: Result<Vec<Image>, Error> = images.into_iter()
-        .map(|image| image.decode())
-        .collect();
+    let items = check_count(images.len(), config)
+        .and_then(|_| images.into_iter()
+            .map(|image| item_result(partial, image.decode(config).map_err(ApiError::from)))
+            .collect::<Result<Vec<_>, _>>());
     
-    match images {
-        Err(e) => Either::B(
-            future::ok(
-                HttpResponse::BadRequest()
-                    .json(Response::Error(format!("{}", e)))
-            )
-        ),
-        Ok(images) => Either::A(
-            store_images(storage, images)
-                .map(|ids| HttpResponse::Ok().json(Response::Ids(ids)))
-        )
+    match items {
+        Err(e) => Either::B(future::err(e)),
+        Ok(items) => Either::A(store_items(storage, items, config.variants.clone(), partial))
     }
 }
 
-fn download_image(uri: Uri) -> impl Future<Item=Image, Error=Error> {
-    let client = Client::default();
-
-    client.get(&uri)
-        .send()
-        .map_err(|e| format_err!("{}", e))
-        // get image format from content type
-        .and_then(|response| {
-            response.headers()
-                .get("Content-Type")
-                .ok_or_else(|| format_err!("No Content-Type header"))
-                .and_then(|header| 
-                    header.to_str()
-                        .map_err(|e| format_err!("Invalid Content-Type header: {}", e))
-                )
-                .and_then(ImageFormat::from_content_type)
-                .map(|format| (response, format))
-        })
-        // receive body
-        .and_then(|(response, format)| {
-            response.concat2()
-                .map_err(|e| format_err!("{}", e))
-                .map(move |body| (body, format))
-        })
-        .and_then(|(body, format)| 
-            Image::decode(&body, format)
-                .map_err(|e| format_err!("Failed to decode image: {}", e))
+/// Location of redirect, it can be relative to current URI
+fn redirect_target(current: &Uri, location: &str) -> Result<Uri, ApiError> {
+    match location.parse::<Uri>() {
+        Ok(uri) if uri.scheme_part().is_some() => return Ok(uri),
+        _ => {}
+    }
+
+    let scheme = current.scheme_str().unwrap_or("http");
+    let authority = current.authority_part().map(|authority| authority.as_str()).unwrap_or_default();
+    let target = if location.starts_with("//") {
+        format!("{}:{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let path = current.path();
+        let directory = &path[..path.rfind('/').map(|pos| pos + 1).unwrap_or(0)];
+        format!("{}://{}{}{}", scheme, authority, directory, location)
+    };
+
+    target.parse::<Uri>()
+        .map_err(|e| ApiError::RemoteFetchFailed(format!("Invalid redirect location {}: {}", location, e)))
+}
+
+/// client only connects to addresses checked by SSRF guard, TLS uses original host
+fn remote_client(remote: &RemoteConfig) -> Client {
+    let connector = Connector::new()
+        .connector(remote::GuardedConnector::new(remote.clone()))
+        .timeout(remote.connect_timeout)
+        .finish();
+
+    Client::build()
+        .connector(connector)
+        .timeout(remote.read_timeout)
+        .header(header::USER_AGENT, remote.user_agent.clone())
+        .finish()
+}
+
+/// Size of received data is returned together with image, also when received data is not an image.
+/// Each location (including redirects) is checked before request is sent, so rejected locations
+/// are reported as such, connector checks address again when it connects.
+fn download_image(
+    client: Client,
+    uri: Uri,
+    config: UploadConfig,
+    remote: RemoteConfig
+) -> impl Future<Item=Received, Error=ApiError> {
+    let source = ImageSource::Remote { url: uri.to_string() };
+    let read_timeout = remote.read_timeout;
+
+    future::loop_fn((uri, 0), move |(uri, redirects)| {
+        let (client, remote) = (client.clone(), remote.clone());
+        let max_redirects = remote.max_redirects;
+        let target = uri.clone();
+
+        web::block(move || remote::resolve(&target, &remote))
+            .map_err(ApiError::from)
+            .and_then({
+                let uri = uri.clone();
+                move |_| client.get(&uri)
+                    .send()
+                    .map_err(|e| ApiError::RemoteFetchFailed(format!("{}", e)))
+            })
+            .and_then(move |response| {
+                if !response.status().is_redirection() {
+                    return Ok(Loop::Break(response));
+                }
+                if redirects >= max_redirects {
+                    return Err(ApiError::RemoteFetchFailed(format!("More than {} redirects", max_redirects)));
+                }
+
+                let location = response.headers()
+                    .get(header::LOCATION)
+                    .and_then(|header| header.to_str().ok())
+                    .ok_or_else(|| ApiError::RemoteFetchFailed("Redirect without location".to_string()))?;
+                Ok(Loop::Continue((redirect_target(&uri, location)?, redirects + 1)))
+            })
+    })
+    // content type is only a hint, it is often missing or generic
+    .and_then(|response| {
+        if !response.status().is_success() {
+            return Err(ApiError::RemoteFetchFailed(format!("Server responded with {}", response.status())));
+        }
+
+        let declared = response.headers()
+            .get("Content-Type")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .and_then(|content_type| ImageFormat::from_content_type(content_type.trim()).ok());
+        let length = response.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        Ok((response, declared, length))
+    })
+    // receive body, oversized bodies are rejected without receiving them when possible
+    .and_then(move |(response, declared, length)| {
+        let (limit, message) = size_limit(0, &config);
+        if length.is_some_and(|length| length > limit) {
+            return Either::B(future::err(ApiError::PayloadTooLarge(message)));
+        }
+
+        let response = response.map_err(|e| ApiError::RemoteFetchFailed(format!("{}", e)));
+        Either::A(
+            Timeout::new(read_limited(response, (limit, message)), read_timeout)
+                .map_err(|e| e.into_inner()
+                    .unwrap_or_else(|| ApiError::RemoteFetchFailed("Timed out while receiving image".to_string())))
+                .then(move |body| -> Result<Received, ApiError> {
+                    Ok(match body {
+                        Ok(body) => decode_item(&body, declared, source, &config),
+                        Err(e) => failed_item(e, limit)
+                    })
+                })
         )
+    })
+}
+
+fn parse_link(link: &str) -> Result<Uri, ApiError> {
+    link.parse::<Uri>()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid uri {}: {}", link, e)))
 }
 
-/// upload from remote location
-fn upload_from_links<S>(storage: Arc<S>, locations: Vec<String>) -> impl Future<Item=HttpResponse, Error=Error>
+/// Upload from remote locations, which are downloaded in parallel.
+/// Results are still handled in order of locations, so they count towards request size in this order.
+fn upload_from_links<S>(
+    storage: Arc<S>,
+    locations: Vec<String>,
+    config: &UploadConfig,
+    remote: &RemoteConfig,
+    partial: bool
+) -> impl Future<Item=HttpResponse, Error=ApiError>
     where S: Storage
 {
-    let links = locations.iter()
-        .map(|link| link.parse::<Uri>())
-        .collect::<Result<Vec<_>, _>>();
+    // in strict mode all links are validated before anything is downloaded
+    let links = check_count(locations.len(), config)
+        .and_then(|_| locations.iter()
+            .map(|link| item_result(partial, parse_link(link)))
+            .collect::<Result<Vec<_>, _>>());
 
+    let client = remote_client(remote);
+    let concurrency = remote.concurrency.max(1);
+    let (config, remote) = (config.clone(), remote.clone());
+    let limits = config.clone();
+    let variants = config.variants.clone();
     match links {
-        Err(e) => Either::B(
-            future::ok(
-                HttpResponse::BadRequest()
-                    .json(Response::Error(format!("Invalid uri: {}", e)))
-            )
-        ),
+        Err(e) => Either::B(future::err(e)),
         Ok(links) => Either::A(
             stream::iter_ok(links)
-                .and_then(|link| 
-                    download_image(link.clone())
-                        .map_err(move |e| format_err!("Failed to download image from {}: {}", link, e))
-                )
-                .collect()
-                .and_then(move |images| store_images(storage, images))
-                .map(|ids| HttpResponse::Ok().json(Response::Ids(ids)))
+                .map(move |link| {
+                    let item = match link {
+                        Ok(link) => {
+                            let context = format!("Failed to download image from {}", link);
+                            Either::A(
+                                download_image(client.clone(), link, config.clone(), remote.clone())
+                                    .then(move |item| -> Result<Received, ApiError> {
+                                        let (result, size) = item.unwrap_or_else(|e| (Err(e), 0));
+                                        Ok((result.map_err(|e| e.context(&context)), size))
+                                    })
+                            )
+                        },
+                        Err(e) => Either::B(future::ok((Err(e), 0)))
+                    };
+                    // failures are handled with other results in order of locations
+                    item
+                })
+                .buffered(concurrency)
+                .fold((Vec::new(), 0), move |items: Items, result| push_item(partial, &limits, items, result))
+                .and_then(move |(items, _)| store_items(storage, items, variants, partial))
         )
     }
 }
 
+/// persist state of job item, failure is only logged, because nobody waits for it
+fn save_job_item<S>(storage: Arc<S>, id: JobId, position: usize, item: JobItem) -> impl Future<Item=(), Error=()>
+    where S: Storage
+{
+    web::block(move || storage.update_job_item(&id, position, &item)
+            .map_err(|e| error!("Failed to update item {} of job {}: {}", position, id, e)))
+        .map_err(|_| ())
+}
+
+/// single location of job
+struct ImportTask<S> {
+    storage: Arc<S>,
+    id: JobId,
+    position: usize,
+    link: Uri,
+    config: UploadConfig
+}
+
+/// Locations of all jobs are processed by one consumer, which limits number of parallel downloads.
+/// Consumer runs on its own arbiter, so it doesn't depend on lifecycle of HTTP workers,
+/// which only get sender of the queue.
+pub struct ImportQueue<S> {
+    sender: Mutex<UnboundedSender<ImportTask<S>>>
+}
+
+impl<S: Storage> ImportQueue<S> {
+    pub fn start(arbiter: &Arbiter, remote: RemoteConfig) -> ImportQueue<S> {
+        let (sender, receiver) = mpsc::unbounded();
+        arbiter.exec_fn(move || Arbiter::spawn(consume_imports(receiver, remote)));
+        ImportQueue {
+            sender: Mutex::new(sender)
+        }
+    }
+
+    fn push(&self, task: ImportTask<S>) -> Result<(), ApiError> {
+        self.sender.lock().unwrap()
+            .unbounded_send(task)
+            .map_err(|_| ApiError::Internal("Import queue is closed".to_string()))
+    }
+}
+
+/// client is shared by all tasks, at most `job_concurrency` of them are processed at once
+fn consume_imports<S>(receiver: UnboundedReceiver<ImportTask<S>>, remote: RemoteConfig) -> impl Future<Item=(), Error=()>
+    where S: Storage
+{
+    let client = remote_client(&remote);
+    let concurrency = remote.job_concurrency.max(1);
+    receiver
+        .map(move |task| import_item(client.clone(), remote.clone(), task))
+        .buffer_unordered(concurrency)
+        .for_each(|_| Ok(()))
+}
+
+/// Download and store single image of job, its state is saved before and after that.
+/// Each image is stored separately, so finished items are available while job is still running.
+fn import_item<S>(client: Client, remote: RemoteConfig, task: ImportTask<S>) -> impl Future<Item=(), Error=()>
+    where S: Storage
+{
+    let ImportTask { storage, id, position, link, config } = task;
+    let url = link.to_string();
+    let running = JobItem::new(url.clone(), JobStatus::Running);
+    let variants = config.variants.clone();
+
+    save_job_item(storage.clone(), id.clone(), position, running)
+        .and_then(move |_| {
+            download_image(client, link, config, remote)
+                .and_then(|(result, _)| result)
+                .and_then({
+                    let storage = storage.clone();
+                    move |upload| store_images(storage, vec![Ok(upload)], variants, false)
+                })
+                .then(move |result| {
+                    let stored = result.and_then(|results| results.into_iter().next()
+                        .unwrap_or_else(|| Err(ApiError::Internal("Storage didn't return stored image".to_string()))));
+                    let item = match stored {
+                        Ok(uploaded) => JobItem::succeeded(url, uploaded),
+                        Err(e) => JobItem::failed(url, &e)
+                    };
+                    save_job_item(storage, id, position, item)
+                })
+        })
+}
+
+/// items which couldn't be queued would stay queued forever, so they are failed with the error
+fn fail_job_items<S>(storage: Arc<S>, id: JobId, items: Vec<(usize, Uri)>, e: ApiError) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let failed: Vec<(usize, JobItem)> = items.into_iter()
+        .map(|(position, link)| (position, JobItem::failed(link.to_string(), &e)))
+        .collect();
+
+    web::block(move || -> Result<(), Error> {
+        for (position, item) in &failed {
+            storage.update_job_item(&id, *position, item)?;
+        }
+        Ok(())
+    })
+    .then(move |result| {
+        if let Err(update) = result {
+            error!("Failed to fail items of job which couldn't be queued: {}", update);
+        }
+        Err(e)
+    })
+}
+
+/// Create job for remote locations and process it in background, job is returned without waiting for it.
+/// Each image is limited by maximal file size, but there is no limit for their total size.
+/// Jobs which are interrupted by restart are not resumed, their unfinished items are failed on startup.
+fn upload_async<S>(
+    storage: Arc<S>,
+    queue: Arc<ImportQueue<S>>,
+    locations: Vec<String>,
+    config: &UploadConfig
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let links = check_count(locations.len(), config)
+        .and_then(|_| locations.iter()
+            .map(|link| parse_link(link))
+            .collect::<Result<Vec<_>, _>>());
+    let links = match links {
+        Ok(links) => links,
+        Err(e) => return Either::B(future::err(e))
+    };
+
+    let job = Job::new(locations);
+    let config = config.clone();
+    Either::A(
+        web::block({
+            let (storage, job) = (storage.clone(), job.clone());
+            move || storage.create_job(&job)
+        })
+        .map_err(ApiError::from)
+        .and_then(move |_| {
+            let mut links = links.into_iter().enumerate();
+            let failed = links.by_ref().find_map(|(position, link)| {
+                let task = ImportTask {
+                    storage: storage.clone(),
+                    id: job.id.clone(),
+                    position,
+                    link: link.clone(),
+                    config: config.clone()
+                };
+                queue.push(task).err().map(|e| ((position, link), e))
+            });
+
+            match failed {
+                None => {
+                    info!("Started job {} with {} images", job.id, job.items.len());
+                    Either::A(future::ok(HttpResponse::Accepted().json(Response::Job(JobInfo::new(job)))))
+                },
+                Some((item, e)) => {
+                    let items = std::iter::once(item).chain(links).collect();
+                    Either::B(fail_job_items(storage, job.id, items, e))
+                }
+            }
+        })
+    )
+}
+
 /// json request handler
 fn upload_json<S: Storage>(
     state: web::Data<Arc<S>>, 
+    config: web::Data<UploadConfig>,
+    remote: web::Data<RemoteConfig>,
+    queue: web::Data<Arc<ImportQueue<S>>>,
+    query: web::Query<UploadQuery>,
     request: web::Json<Request>
-) -> impl Future<Item=HttpResponse, Error=Error> {
+) -> impl Future<Item=HttpResponse, Error=ApiError> {
 
     let storage = state.get_ref().clone();
     match request.into_inner() {
-        Request::Base64 { images } => Either::A(upload_base64(storage, images)),
-        Request::Remote { locations } => Either::B(upload_from_links(storage, locations))
+        Request::Base64 { .. } if query.asynchronous => {
+            let error = ApiError::BadRequest("Only remote uploads can be asynchronous".to_string());
+            Either::B(Either::B(future::err(error)))
+        },
+        Request::Base64 { images } => Either::A(Either::A(upload_base64(storage, images, config.get_ref(), query.partial))),
+        Request::Remote { locations } if query.asynchronous => {
+            Either::B(Either::A(upload_async(storage, queue.get_ref().clone(), locations, config.get_ref())))
+        },
+        Request::Remote { locations } => {
+            Either::A(Either::B(upload_from_links(storage, locations, config.get_ref(), remote.get_ref(), query.partial)))
+        }
     }
 }
 
-pub fn bind<S>(path: &'static str) -> impl HttpServiceFactory
+pub fn bind<S>(
+    path: &'static str,
+    config: UploadConfig,
+    remote: RemoteConfig,
+    queue: Arc<ImportQueue<S>>
+) -> impl HttpServiceFactory
     where S: Storage
 {
+    let max_request_size = config.max_request_size;
     web::resource(path)
-        .data(web::Json::<Request>::configure(|cfg| {
-            cfg.limit(1024 * 1024) // 1MB
+        .data(config)
+        .data(remote)
+        .data(queue)
+        .data(web::Json::<Request>::configure(move |cfg| {
+            cfg.limit(max_request_size)
+                .error_handler(move |e, _| {
+                    let error = match e {
+                        JsonPayloadError::Overflow => {
+                            ApiError::PayloadTooLarge(format!("Request is larger than {} bytes", max_request_size))
+                        },
+                        e => ApiError::BadRequest(format!("{}", e))
+                    };
+                    error.into()
+                })
         }))
         .route(web::get().to(upload_form))
         .route(
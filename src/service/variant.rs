@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header;
+use futures::future::{self, Future, Either};
+use failure::Error;
+use log::warn;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::storage::Storage;
+use crate::models::{ImageId, Image, Variant};
+use super::negotiation::OutputFormat;
+use super::conditional::{Conditions, Validators};
+
+
+#[derive(Deserialize)]
+struct VariantQuery {
+    format: Option<String>
+}
+
+/// Stored variant of image, it is only generated from original
+/// if it is missing (e.g. image was uploaded before variant was configured).
+/// Generated variant is stored, so it is generated only once.
+fn load_variant<S>(storage: &S, id: &ImageId, variant: &Variant) -> Result<Image, Error>
+    where S: Storage
+{
+    if let Some(image) = storage.load_variant(id, &variant.name)? {
+        return Ok(image);
+    }
+
+    let image = storage.load(id)?.variant(variant)?;
+    // variant can be generated again, so failure is not returned to client
+    if let Err(e) = storage.store_variant(id, &variant.name, &image) {
+        warn!("Failed to store variant {} of image with id={}: {}", variant.name, id, e);
+    }
+    Ok(image)
+}
+
+/// Variant converted to output format, nothing is loaded if client already has it.
+/// Format of stored variant is known from metadata, it is derived format of image.
+fn converted_variant<S>(
+    storage: &S,
+    id: &ImageId,
+    variant: &Variant,
+    output: &OutputFormat,
+    conditions: &Conditions
+) -> Result<(Validators, Option<Image>), Error>
+    where S: Storage
+{
+    let metadata = storage.metadata(id)?;
+    let format = output.choose(metadata.format.derived());
+    let transformation = format!("{}-{}x{}-{}.{}", variant.name, variant.size.0, variant.size.1, variant.mode, format);
+    let validators = Validators::new(id, &metadata, Some(&transformation));
+    if conditions.is_fresh(&validators) {
+        return Ok((validators, None));
+    }
+
+    let image = load_variant(storage, id, variant)?.convert(format)?;
+    Ok((validators, Some(image)))
+}
+
+fn download_variant<S>(
+    state: web::Data<Arc<S>>,
+    variants: web::Data<Vec<Variant>>,
+    info: web::Path<(String, String)>,
+    query: web::Query<VariantQuery>,
+    request: HttpRequest
+) -> impl Future<Item=HttpResponse, Error=ApiError>
+    where S: Storage
+{
+    let (id, name) = (&info.0, &info.1);
+    let params = id.parse::<ImageId>()
+        .map_err(ApiError::from)
+        .and_then(|id| {
+            variants.iter()
+                .find(|variant| &variant.name == name)
+                .map(|variant| (id, variant.clone()))
+                .ok_or_else(|| ApiError::NotFound(format!("Unknown variant: {}", name)))
+        })
+        .and_then(|(id, variant)| {
+            OutputFormat::from_request(&request, query.format.as_deref())
+                .map(|output| (id, variant, output))
+                .map_err(ApiError::from)
+        });
+
+    let (id, variant, output) = match params {
+        Ok(params) => params,
+        Err(e) => return Either::B(future::err(e))
+    };
+
+    let negotiated = output.is_negotiated();
+    let conditions = Conditions::from_request(&request);
+    let storage = state.get_ref().clone();
+    Either::A(
+        web::block(move || converted_variant(storage.as_ref(), &id, &variant, &output, &conditions))
+        .map_err(|e| ApiError::from(e).context("Failed to load image"))
+        .map(move |(validators, image)| {
+            let mut response = match image {
+                Some(_) => HttpResponse::Ok(),
+                None => HttpResponse::NotModified()
+            };
+            validators.apply(&mut response);
+            if negotiated {
+                response.header(header::VARY, "Accept");
+            }
+
+            let image = match image {
+                Some(image) => image,
+                None => return response.finish()
+            };
+            response
+                .content_type(image.format().content_type())
+                .content_length(image.data().len() as u64)
+                .body(image.into_data())
+        })
+    )
+}
+
+/// NOTE: should be registered after other "{id}/..." routes (e.g. preview and info), variants can't use their names
+pub fn bind<S>(prefix: &str, variants: Vec<Variant>) -> impl HttpServiceFactory
+    where S: Storage
+{
+    let path = prefix.to_string() + "/{id}/{variant}";
+    web::resource(&path)
+        .data(variants)
+        .route(web::get().to_async(download_variant::<S>))
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use failure::Error;
+
+use super::Storage;
+use crate::error::ApiError;
+use crate::models::{
+    Image, ImageId, ImageFormat, ImageMetadata, ImageSource, Upload, UploadedImage, DeleteToken,
+    Job, JobId, JobItem
+};
+
+const INDEX_FILE: &str = "index.log";
+const IMAGES_DIR: &str = "images";
+const JOBS_DIR: &str = "jobs";
+const VARIANTS_DIR: &str = "variants";
+
+/// Index is an append-only log of these records,
+/// it is replayed (and compacted) when storage is opened
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum IndexRecord {
+    Store {
+        id: ImageId,
+        format: ImageFormat,
+        delete_token: DeleteToken,
+        /// missing in records written before metadata was introduced
+        #[serde(default)]
+        metadata: Option<ImageMetadata>
+    },
+    Delete {
+        id: ImageId
+    }
+}
+
+struct IndexEntry {
+    delete_token: DeleteToken,
+    metadata: ImageMetadata
+}
+
+#[derive(Default)]
+struct Index {
+    images: HashMap<ImageId, IndexEntry>,
+    /// number of images which share a file with given hash
+    refcounts: HashMap<String, usize>
+}
+
+impl Index {
+    fn insert(&mut self, id: ImageId, entry: IndexEntry) {
+        *self.refcounts.entry(entry.metadata.hash.clone()).or_insert(0) += 1;
+        self.images.insert(id, entry);
+    }
+
+    /// returns hash of file if it is not used anymore
+    fn remove(&mut self, id: &ImageId) -> Option<String> {
+        let entry = self.images.remove(id)?;
+        let refcount = self.refcounts.get_mut(&entry.metadata.hash)?;
+        *refcount -= 1;
+        if *refcount > 0 {
+            return None;
+        }
+
+        self.refcounts.remove(&entry.metadata.hash);
+        Some(entry.metadata.hash)
+    }
+}
+
+/// Stores images as plain files in `{root}/images/{ab}/{cd}/{abcd...}`, named by hash of data,
+/// so identical images share a file. Files are written to temporary location first
+/// and then renamed, so partially written images are never visible.
+pub struct FilesystemStorage {
+    root: PathBuf,
+    index: RwLock<Index>,
+    journal: Mutex<File>,
+    /// held while job file is read and rewritten, so concurrent item updates aren't lost
+    jobs: Mutex<()>
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // name is unique, so concurrent writers of the same file don't interfere
+    let tmp = path.with_extension(format!("{}.tmp", ImageId::generate()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// files are spread over two levels of directories, so none of them gets too large
+fn file_path(root: &Path, name: &str) -> PathBuf {
+    root.join(IMAGES_DIR)
+        .join(name.get(0..2).unwrap_or("_"))
+        .join(name.get(2..4).unwrap_or("_"))
+        .join(name)
+}
+
+/// variants of file are kept in `{root}/variants/{ab}/{cd}/{abcd...}/{name}`,
+/// their format is detected from data when they are loaded
+fn variants_path(root: &Path, hash: &str) -> PathBuf {
+    root.join(VARIANTS_DIR)
+        .join(hash.get(0..2).unwrap_or("_"))
+        .join(hash.get(2..4).unwrap_or("_"))
+        .join(hash)
+}
+
+/// images stored before deduplication was introduced are named by id,
+/// they are moved to their new location when storage is opened
+fn migrate_legacy_file(root: &Path, id: &ImageId, hash: &str) -> Result<(), Error> {
+    let legacy = file_path(root, id.as_str());
+    if !legacy.exists() {
+        return Ok(());
+    }
+
+    let path = file_path(root, hash);
+    if path.exists() {
+        fs::remove_file(&legacy)?;
+    } else {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(&legacy, &path)?;
+    }
+    Ok(())
+}
+
+/// compute metadata of image stored before metadata was introduced
+fn legacy_metadata(path: &Path, format: ImageFormat) -> Result<ImageMetadata, Error> {
+    let data = fs::read(path)?;
+    let modified: DateTime<Utc> = fs::metadata(path)?.modified()?.into();
+    let upload = Upload::decode(&data, format, ImageSource::Unknown)?;
+
+    Ok(ImageMetadata {
+        created_at: modified,
+        ..upload.metadata
+    })
+}
+
+fn serialize_records<'a, I>(records: I) -> Result<Vec<u8>, Error>
+    where I: IntoIterator<Item=&'a IndexRecord>
+{
+    let mut data = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut data, record)?;
+        data.push(b'\n');
+    }
+    Ok(data)
+}
+
+impl FilesystemStorage {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join(IMAGES_DIR))?;
+
+        let index_path = root.join(INDEX_FILE);
+        let mut records = HashMap::new();
+        if index_path.exists() {
+            let reader = BufReader::new(File::open(&index_path)?);
+            for line in reader.lines() {
+                let line = line?;
+                match serde_json::from_str::<IndexRecord>(&line) {
+                    Ok(IndexRecord::Store { id, format, delete_token, metadata }) => {
+                        records.insert(id, (format, delete_token, metadata));
+                    },
+                    Ok(IndexRecord::Delete { id }) => {
+                        records.remove(&id);
+                    },
+                    // most likely the last record was not written completely
+                    Err(e) => warn!("Skipping invalid index record {:?}: {}", line, e)
+                }
+            }
+        }
+
+        let mut index = Index::default();
+        for (id, (format, delete_token, metadata)) in records {
+            let metadata = match metadata {
+                Some(metadata) => metadata,
+                None => match legacy_metadata(&file_path(&root, id.as_str()), format) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn!("Skipping image {} without metadata: {}", id, e);
+                        continue;
+                    }
+                }
+            };
+
+            if let Err(e) = migrate_legacy_file(&root, &id, &metadata.hash) {
+                warn!("Failed to move image {}: {}", id, e);
+                continue;
+            }
+            index.insert(id, IndexEntry { delete_token, metadata });
+        }
+
+        // compact index, so deleted images don't take space in it
+        let records: Vec<IndexRecord> = index.images.iter()
+            .map(|(id, entry): (&ImageId, &IndexEntry)| IndexRecord::Store {
+                id: id.clone(),
+                format: entry.metadata.format,
+                delete_token: entry.delete_token.clone(),
+                metadata: Some(entry.metadata.clone())
+            })
+            .collect();
+        write_atomically(&index_path, &serialize_records(&records)?)?;
+
+        let journal = OpenOptions::new()
+            .append(true)
+            .open(&index_path)?;
+
+        info!("Opened filesystem storage at {:?} with {} images ({} files)",
+              root, index.images.len(), index.refcounts.len());
+        Ok(FilesystemStorage {
+            root,
+            index: RwLock::new(index),
+            journal: Mutex::new(journal),
+            jobs: Mutex::new(())
+        })
+    }
+
+    fn file_path(&self, hash: &str) -> PathBuf {
+        file_path(&self.root, hash)
+    }
+
+    /// each job is kept in its own JSON file `{root}/jobs/{id}.json`
+    fn job_path(&self, id: &JobId) -> PathBuf {
+        self.root.join(JOBS_DIR).join(format!("{}.json", id))
+    }
+
+    fn read_job(&self, id: &JobId) -> Result<Job, Error> {
+        let data = match fs::read(self.job_path(id)) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ApiError::NotFound(format!("No job with id={}", id)).into());
+            },
+            Err(e) => return Err(e.into())
+        };
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    fn write_job(&self, job: &Job) -> Result<(), Error> {
+        write_atomically(&self.job_path(&job.id), &serde_json::to_vec(job)?)
+    }
+
+    /// variants are written first, so file never exists without them
+    fn write_files(&self, upload: &Upload) -> Result<(), Error> {
+        let variants = variants_path(&self.root, &upload.metadata.hash);
+        for (name, variant) in &upload.variants {
+            write_atomically(&variants.join(name), variant.data())?;
+        }
+        write_atomically(&self.file_path(&upload.metadata.hash), upload.image.data())
+    }
+
+    fn append(&self, records: &[IndexRecord]) -> Result<(), Error> {
+        let data = serialize_records(records)?;
+        let mut journal = self.journal.lock().unwrap();
+        journal.write_all(&data)?;
+        journal.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Storage for FilesystemStorage {
+    fn store(&self, images: Vec<Upload>) -> Result<Vec<UploadedImage>, Error> {
+        let mut uploaded = Vec::with_capacity(images.len());
+        let mut records = Vec::with_capacity(images.len());
+
+        // files are named by hash, so they are written before index is locked
+        // (concurrent uploads of the same image just replace file with identical data)
+        let stored: Vec<bool> = {
+            let index = self.index.read().unwrap();
+            images.iter().map(|upload| index.refcounts.contains_key(&upload.metadata.hash)).collect()
+        };
+        for (upload, _) in images.iter().zip(stored).filter(|(_, stored)| !stored) {
+            self.write_files(upload)?;
+        }
+
+        // index is only locked to add images, concurrent delete could have removed
+        // shared file in the meantime, in that case it is written again
+        let mut index = self.index.write().unwrap();
+        for upload in images {
+            let image_uploaded = UploadedImage::generate();
+            let hash = &upload.metadata.hash;
+            if !index.refcounts.contains_key(hash) && !self.file_path(hash).exists() {
+                self.write_files(&upload)?;
+            }
+
+            records.push(IndexRecord::Store {
+                id: image_uploaded.id.clone(),
+                format: upload.image.format(),
+                delete_token: image_uploaded.delete_token.clone(),
+                metadata: Some(upload.metadata)
+            });
+            uploaded.push(image_uploaded);
+        }
+
+        // images become visible only after they are added to index
+        self.append(&records)?;
+        for record in records {
+            if let IndexRecord::Store { id, delete_token, metadata: Some(metadata), .. } = record {
+                index.insert(id, IndexEntry { delete_token, metadata });
+            }
+        }
+
+        info!("Successfully stored {} images in {:?}", uploaded.len(), self.root);
+        Ok(uploaded)
+    }
+
+    fn load(&self, id: &ImageId) -> Result<Image, Error> {
+        let metadata = self.metadata(id)?;
+        let data = fs::read(self.file_path(&metadata.hash))?;
+        Ok(Image::from_raw(data, metadata.format))
+    }
+
+    fn read_range(&self, id: &ImageId, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+        // open file can still be read after it is removed by concurrent delete
+        let metadata = self.metadata(id)?;
+        let mut file = File::open(self.file_path(&metadata.hash))?;
+        file.seek(SeekFrom::Start(range.start))?;
+        Ok(Box::new(file.take(range.end - range.start)))
+    }
+
+    fn metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+        self.index.read().unwrap()
+            .images.get(id)
+            .map(|entry| entry.metadata.clone())
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)).into())
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, Error> {
+        Ok(self.index.read().unwrap().refcounts.contains_key(hash))
+    }
+
+    fn load_variant(&self, id: &ImageId, name: &str) -> Result<Option<Image>, Error> {
+        let metadata = self.metadata(id)?;
+        let data = match fs::read(variants_path(&self.root, &metadata.hash).join(name)) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+
+        let format = ImageFormat::detect(&data, None)?;
+        Ok(Some(Image::from_raw(data, format)))
+    }
+
+    fn store_variant(&self, id: &ImageId, name: &str, variant: &Image) -> Result<(), Error> {
+        // index is locked, so concurrent delete doesn't leave variant of removed file
+        let index = self.index.read().unwrap();
+        let hash = &index.images.get(id)
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?
+            .metadata.hash;
+        write_atomically(&variants_path(&self.root, hash).join(name), variant.data())
+    }
+
+    fn delete(&self, id: &ImageId, token: &str) -> Result<(), Error> {
+        let mut index = self.index.write().unwrap();
+        let authorized = index.images.get(id)
+            .map(|entry| entry.delete_token.matches(token))
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?;
+
+        if !authorized {
+            return Err(ApiError::Forbidden("Invalid delete token".to_string()).into());
+        }
+
+        self.append(&[IndexRecord::Delete { id: id.clone() }])?;
+        // file is removed only when no other image shares it
+        if let Some(hash) = index.remove(id) {
+            match fs::remove_file(self.file_path(&hash)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                other => other?
+            }
+            if let Err(e) = fs::remove_dir_all(variants_path(&self.root, &hash)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove variants of {}: {}", hash, e);
+                }
+            }
+        }
+
+        info!("Deleted image with id={}", id);
+        Ok(())
+    }
+
+    fn create_job(&self, job: &Job) -> Result<(), Error> {
+        let _lock = self.jobs.lock().unwrap();
+        self.write_job(job)
+    }
+
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error> {
+        let _lock = self.jobs.lock().unwrap();
+        let mut job = self.read_job(id)?;
+        let slot = job.items.get_mut(position)
+            .ok_or_else(|| ApiError::NotFound(format!("Job with id={} doesn't have item {}", id, position)))?;
+        *slot = item.clone();
+        self.write_job(&job)
+    }
+
+    fn interrupt_jobs(&self) -> Result<usize, Error> {
+        let _lock = self.jobs.lock().unwrap();
+        let entries = match fs::read_dir(self.root.join(JOBS_DIR)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into())
+        };
+
+        let mut count = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let mut job: Job = serde_json::from_slice(&fs::read(&path)?)?;
+            let mut changed = false;
+            for item in job.items.iter_mut().filter(|item| !item.status.is_finished()) {
+                *item = JobItem::interrupted(item.url.clone());
+                changed = true;
+                count += 1;
+            }
+            if changed {
+                self.write_job(&job)?;
+            }
+        }
+        Ok(count)
+    }
+
+    fn load_job(&self, id: &JobId) -> Result<Job, Error> {
+        let _lock = self.jobs.lock().unwrap();
+        self.read_job(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Read;
+
+    use super::{FilesystemStorage, file_path, variants_path};
+    use crate::storage::Storage;
+    use crate::error::ApiError;
+    use crate::models::{Upload, ImageFormat, ImageId, ImageSource, Job, JobId, JobItem, JobStatus};
+
+    const TEST_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/servo.png"));
+
+    #[test]
+    fn store_load_delete() {
+        let root = std::env::temp_dir().join(format!("image-upload-{}", ImageId::generate()));
+        let upload = Upload::decode(TEST_IMAGE, ImageFormat::PNG, ImageSource::Base64).unwrap()
+            .with_variants(&["thumb=16".parse().unwrap()])
+            .unwrap();
+
+        let storage = FilesystemStorage::open(&root).unwrap();
+        let uploaded = storage.store(vec![upload.clone(), upload.clone()]).unwrap();
+        assert_eq!(storage.load(&uploaded[0].id).unwrap().data(), TEST_IMAGE);
+        let mut part = Vec::new();
+        storage.read_range(&uploaded[0].id, 10..20).unwrap().read_to_end(&mut part).unwrap();
+        assert_eq!(&part[..], &TEST_IMAGE[10..20]);
+        storage.delete(&uploaded[0].id, uploaded[0].delete_token.as_str()).unwrap();
+        assert!(storage.load(&uploaded[0].id).is_err());
+        drop(storage);
+
+        // index should survive restart
+        let storage = FilesystemStorage::open(&root).unwrap();
+        assert!(storage.load(&uploaded[0].id).is_err());
+        assert_eq!(storage.load(&uploaded[1].id).unwrap().data(), TEST_IMAGE);
+        assert_eq!(storage.metadata(&uploaded[1].id).unwrap(), upload.metadata);
+        assert!(storage.delete(&uploaded[1].id, "wrong").is_err());
+        let thumb = storage.load_variant(&uploaded[1].id, "thumb").unwrap().unwrap();
+        assert_eq!(thumb.data(), upload.variants[0].1.data());
+        assert_eq!(thumb.format(), ImageFormat::PNG);
+        assert!(storage.load_variant(&uploaded[1].id, "small").unwrap().is_none());
+        storage.store_variant(&uploaded[1].id, "small", &thumb).unwrap();
+        assert_eq!(storage.load_variant(&uploaded[1].id, "small").unwrap().unwrap().data(), thumb.data());
+
+        // identical images share one file, which is removed with the last of them
+        let path = file_path(&root, &upload.metadata.hash);
+        assert!(path.exists());
+        storage.delete(&uploaded[1].id, uploaded[1].delete_token.as_str()).unwrap();
+        assert!(!path.exists());
+        assert!(!variants_path(&root, &upload.metadata.hash).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn job_items() {
+        let root = std::env::temp_dir().join(format!("image-upload-{}", ImageId::generate()));
+        let storage = FilesystemStorage::open(&root).unwrap();
+
+        let job = Job::new(vec!["http://example.com/a.png".to_string(), "http://example.com/b.png".to_string()]);
+        storage.create_job(&job).unwrap();
+        assert_eq!(storage.load_job(&job.id).unwrap(), job);
+        assert_eq!(job.status(), JobStatus::Queued);
+
+        let failed = JobItem::failed(job.items[1].url.clone(), &ApiError::RemoteFetchFailed("404".to_string()));
+        storage.update_job_item(&job.id, 1, &failed).unwrap();
+        assert!(storage.update_job_item(&job.id, 2, &failed).is_err());
+        drop(storage);
+
+        let storage = FilesystemStorage::open(&root).unwrap();
+        let loaded = storage.load_job(&job.id).unwrap();
+        assert_eq!(loaded.items[0], job.items[0]);
+        assert_eq!(loaded.items[1], failed);
+        assert_eq!(loaded.status(), JobStatus::Running);
+        assert!(storage.load_job(&JobId::generate()).is_err());
+
+        // unfinished items are failed after restart, finished ones are kept
+        assert_eq!(storage.interrupt_jobs().unwrap(), 1);
+        let loaded = storage.load_job(&job.id).unwrap();
+        assert_eq!(loaded.items[0].status, JobStatus::Failed);
+        assert_eq!(loaded.items[0].error.as_ref().unwrap().code, "interrupted");
+        assert_eq!(loaded.items[1], failed);
+        assert_eq!(storage.interrupt_jobs().unwrap(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
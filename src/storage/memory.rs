@@ -1,39 +1,175 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::ops::Range;
 use std::sync::RwLock;
 
-use failure::{Error, format_err};
+use failure::Error;
 
 use super::Storage;
-use crate::models::{Image, ImageId};
+use crate::error::ApiError;
+use crate::models::{Image, ImageId, ImageMetadata, Upload, UploadedImage, DeleteToken, Job, JobId, JobItem};
+
+struct Entry {
+    metadata: ImageMetadata,
+    delete_token: DeleteToken
+}
+
+/// Blob is shared by all images with the same hash
+struct Blob {
+    image: Image,
+    variants: HashMap<String, Image>,
+    refcount: usize
+}
+
+#[derive(Default)]
+struct Table {
+    images: HashMap<ImageId, Entry>,
+    blobs: HashMap<String, Blob>,
+    jobs: HashMap<JobId, Job>
+}
 
 #[derive(Default)]
 pub struct MemoryStorage {
-    table: RwLock<Vec<Image>>
+    table: RwLock<Table>
 }
 
 impl MemoryStorage {
-    pub fn with_image(image: Image) -> Self {
-        MemoryStorage {
-            table: RwLock::new(vec![image])
-        }
+    fn not_found(id: &ImageId) -> Error {
+        ApiError::NotFound(format!("Image with id={} not found", id)).into()
+    }
+
+    fn job_not_found(id: &JobId) -> Error {
+        ApiError::NotFound(format!("Job with id={} not found", id)).into()
+    }
+
+    /// number of distinct images which are stored
+    pub fn blob_count(&self) -> usize {
+        self.table.read().unwrap().blobs.len()
     }
 }
 
 impl Storage for MemoryStorage {
-    fn store(&self, images: Vec<Image>) -> Result<Vec<ImageId>, Error> {
+    fn store(&self, images: Vec<Upload>) -> Result<Vec<UploadedImage>, Error> {
         let mut table = self.table.write().unwrap();
-        let start = table.len() as i32;
-        table.extend(images.into_iter());
-        let end = table.len() as i32;
-        let ids = (start..end).into_iter()
-            .map(ImageId)
+        let uploaded = images.into_iter()
+            .map(|upload| {
+                let uploaded = UploadedImage::generate();
+                let (image, variants) = (upload.image, upload.variants);
+                table.blobs.entry(upload.metadata.hash.clone())
+                    .or_insert_with(|| Blob { image, variants: variants.into_iter().collect(), refcount: 0 })
+                    .refcount += 1;
+
+                table.images.insert(uploaded.id.clone(), Entry {
+                    metadata: upload.metadata,
+                    delete_token: uploaded.delete_token.clone()
+                });
+                uploaded
+            })
             .collect();
-        Ok(ids)
+        Ok(uploaded)
+    }
+
+    fn load(&self, id: &ImageId) -> Result<Image, Error> {
+        let table = self.table.read().unwrap();
+        table.images.get(id)
+            .and_then(|entry| table.blobs.get(&entry.metadata.hash))
+            .map(|blob| blob.image.clone())
+            .ok_or_else(|| MemoryStorage::not_found(id))
+    }
+
+    fn read_range(&self, id: &ImageId, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+        let image = self.load(id)?;
+        let data = image.data()[range.start as usize..range.end as usize].to_vec();
+        Ok(Box::new(Cursor::new(data)))
     }
 
-    fn load(&self, id: ImageId) -> Result<Image, Error> {
+    fn metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
         let table = self.table.read().unwrap();
-        table.get(id.inner() as usize)
+        table.images.get(id)
+            .map(|entry| entry.metadata.clone())
+            .ok_or_else(|| MemoryStorage::not_found(id))
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, Error> {
+        Ok(self.table.read().unwrap().blobs.contains_key(hash))
+    }
+
+    fn load_variant(&self, id: &ImageId, name: &str) -> Result<Option<Image>, Error> {
+        let table = self.table.read().unwrap();
+        table.images.get(id)
+            .and_then(|entry| table.blobs.get(&entry.metadata.hash))
+            .map(|blob| blob.variants.get(name).cloned())
+            .ok_or_else(|| MemoryStorage::not_found(id))
+    }
+
+    fn store_variant(&self, id: &ImageId, name: &str, variant: &Image) -> Result<(), Error> {
+        let mut table = self.table.write().unwrap();
+        let hash = table.images.get(id)
+            .map(|entry| entry.metadata.hash.clone())
+            .ok_or_else(|| MemoryStorage::not_found(id))?;
+        if let Some(blob) = table.blobs.get_mut(&hash) {
+            blob.variants.entry(name.to_string()).or_insert_with(|| variant.clone());
+        }
+        Ok(())
+    }
+
+    fn delete(&self, id: &ImageId, token: &str) -> Result<(), Error> {
+        let mut table = self.table.write().unwrap();
+        let authorized = table.images.get(id)
+            .map(|entry| entry.delete_token.matches(token))
+            .ok_or_else(|| MemoryStorage::not_found(id))?;
+
+        if !authorized {
+            return Err(ApiError::Forbidden("Invalid delete token".to_string()).into());
+        }
+
+        let entry = table.images.remove(id).unwrap();
+        let unused = match table.blobs.get_mut(&entry.metadata.hash) {
+            Some(blob) => {
+                blob.refcount -= 1;
+                blob.refcount == 0
+            },
+            None => false
+        };
+
+        if unused {
+            table.blobs.remove(&entry.metadata.hash);
+        }
+        Ok(())
+    }
+
+    fn create_job(&self, job: &Job) -> Result<(), Error> {
+        self.table.write().unwrap().jobs.insert(job.id.clone(), job.clone());
+        Ok(())
+    }
+
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error> {
+        let mut table = self.table.write().unwrap();
+        let slot = table.jobs.get_mut(id)
+            .and_then(|job| job.items.get_mut(position))
+            .ok_or_else(|| MemoryStorage::job_not_found(id))?;
+        *slot = item.clone();
+        Ok(())
+    }
+
+    fn interrupt_jobs(&self) -> Result<usize, Error> {
+        let mut table = self.table.write().unwrap();
+        let items = table.jobs.values_mut()
+            .flat_map(|job| job.items.iter_mut())
+            .filter(|item| !item.status.is_finished());
+
+        let mut count = 0;
+        for item in items {
+            *item = JobItem::interrupted(item.url.clone());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn load_job(&self, id: &JobId) -> Result<Job, Error> {
+        self.table.read().unwrap()
+            .jobs.get(id)
             .cloned()
-            .ok_or_else(|| format_err!("Image with id={} not found", id.inner()))
+            .ok_or_else(|| MemoryStorage::job_not_found(id))
     }
 }
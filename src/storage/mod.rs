@@ -1,15 +1,45 @@
+use std::io::Read;
+use std::ops::Range;
+
 use failure::Error;
-use crate::models::{ImageId, Image};
+use crate::models::{ImageId, Image, ImageMetadata, Upload, UploadedImage, Job, JobId, JobItem};
 
 #[cfg(test)]
 mod memory;
 mod postgres;
+mod filesystem;
+mod s3;
 
 #[cfg(test)]
 pub use self::memory::MemoryStorage;
 pub use self::postgres::PostgresStorage;
+pub use self::filesystem::FilesystemStorage;
+pub use self::s3::{S3Bucket, S3Storage};
 
 pub trait Storage: 'static + Send + Sync {
-    fn store(&self, images: Vec<Image>) -> Result<Vec<ImageId>, Error>;
-    fn load(&self, id: ImageId) -> Result<Image, Error>;
+    /// store images, each of them gets its own delete token
+    fn store(&self, images: Vec<Upload>) -> Result<Vec<UploadedImage>, Error>;
+    fn load(&self, id: &ImageId) -> Result<Image, Error>;
+    /// Reader of part of original data, range is within size of image (so it is never empty).
+    /// Location of data is resolved once, reader then keeps reading the same data,
+    /// backends read only this part instead of whole image
+    fn read_range(&self, id: &ImageId, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error>;
+    fn metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error>;
+    /// whether data with given hash is already stored (together with its variants)
+    fn contains(&self, hash: &str) -> Result<bool, Error>;
+    /// variant which was stored with image, None if it wasn't generated (e.g. configured after upload)
+    fn load_variant(&self, id: &ImageId, name: &str) -> Result<Option<Image>, Error>;
+    /// store variant which was generated after upload, it is shared by all images with the same data
+    fn store_variant(&self, id: &ImageId, name: &str, variant: &Image) -> Result<(), Error>;
+    /// delete image if token matches the one given on upload
+    fn delete(&self, id: &ImageId, token: &str) -> Result<(), Error>;
+
+    /// persist new remote import job
+    fn create_job(&self, job: &Job) -> Result<(), Error>;
+    /// items are updated independently, because they are downloaded concurrently
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error>;
+    fn load_job(&self, id: &JobId) -> Result<Job, Error>;
+    /// mark items which are not finished as failed, it is called on startup,
+    /// because jobs are not resumed. Number of such items is returned
+    fn interrupt_jobs(&self) -> Result<usize, Error>;
 }
@@ -1,11 +1,23 @@
+use std::io::{Cursor, Read};
+use std::ops::Range;
+
+use chrono::{DateTime, Utc};
 use log::info;
-use diesel::r2d2::{self, ConnectionManager};
+use diesel::r2d2::{self, ConnectionManager, PooledConnection};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use num_traits::FromPrimitive;
+use diesel::dsl::sql;
+use diesel::sql_types::{Binary, Integer, Nullable, Text};
 use failure::{Error, format_err};
+use num_traits::FromPrimitive;
 
-use crate::models::{ImageId, Image, ImageFormat, LoadedImage};
+use crate::error::ApiError;
+use crate::models::{
+    ImageId, Image, ImageMetadata, MetadataRow, METADATA_COLUMNS,
+    NewVariant, Upload, UploadedImage, DeleteToken, ImageFormat,
+    Job, JobId, JobItem, JobStatus, NewJob, JobItemRow
+};
+use crate::schema::{images, blobs, variants, jobs, job_items};
 use super::Storage;
 
 type Pool<C> = r2d2::Pool<ConnectionManager<C>>;
@@ -14,54 +26,383 @@ pub struct PostgresStorage {
     connections: Pool<PgConnection>
 }
 
+/// Image together with blob, which holds its data
+pub(crate) struct ImageRow {
+    pub metadata: ImageMetadata,
+    pub object_key: String,
+    /// None if data is stored outside of database
+    pub data: Option<Vec<u8>>
+}
+
+/// Stored variant of image
+pub(crate) struct VariantRow {
+    pub format: ImageFormat,
+    pub object_key: String,
+    /// None if data is stored outside of database
+    pub data: Option<Vec<u8>>
+}
+
+/// Blob which was inserted or which already existed
+#[derive(QueryableByName)]
+struct BlobRow {
+    #[sql_type = "Integer"]
+    blob_id: i32,
+    #[sql_type = "Text"]
+    object_key: String
+}
+
+/// Insert blob or add reference to existing blob with the same hash. It is a single statement
+/// (backed by unique index), so concurrent uploads of identical data can't create two blobs.
+/// Diesel can't express conflict target of partial index, hence raw query.
+fn upsert_blob(connection: &PgConnection, hash: &str, object_key: &str, data: Option<&[u8]>) -> Result<BlobRow, Error> {
+    let row = diesel::sql_query(
+        "INSERT INTO blobs (hash, object_key, data, refcount) VALUES ($1, $2, $3, 1) \
+         ON CONFLICT (hash) WHERE hash <> '' DO UPDATE SET refcount = blobs.refcount + 1 \
+         RETURNING blob_id, object_key"
+    )
+        .bind::<Text, _>(hash)
+        .bind::<Text, _>(object_key)
+        .bind::<Nullable<Binary>, _>(data)
+        .get_result::<BlobRow>(connection)?;
+    Ok(row)
+}
+
 impl PostgresStorage {
     pub fn new(url: &str) -> Result<Self, Error> {
         let manager = ConnectionManager::new(url);
         let pool = r2d2::Pool::builder().build(manager)?;
-        
+
         Ok(PostgresStorage {
             connections: pool
         })
     }
+
+    fn connection(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Error> {
+        self.connections.get()
+            .map_err(|e| ApiError::StorageUnavailable(format!("Failed to get connection: {}", e)).into())
+    }
+
+    /// Store images, identical images share the same blob (and its variants).
+    /// Data stored outside of database has to be written under `object_keys` (one for each upload,
+    /// variants use "{key}.{name}") before, flag of each image tells whether its blob was created,
+    /// otherwise its objects are not referenced.
+    pub(super) fn insert(
+        &self,
+        uploads: &[Upload],
+        object_keys: Option<&[String]>
+    ) -> Result<Vec<(UploadedImage, bool)>, Error> {
+        let connection = self.connection()?;
+        connection.transaction::<_, Error, _>(|| {
+            let mut uploaded = Vec::with_capacity(uploads.len());
+            for (index, upload) in uploads.iter().enumerate() {
+                // keys are random, so removing blob never affects blob with the same hash
+                let object_key = match object_keys {
+                    Some(keys) => keys[index].clone(),
+                    None => ImageId::generate().0
+                };
+                let external = object_keys.is_some();
+                let data = Some(upload.image.data()).filter(|_| !external);
+
+                let blob = upsert_blob(&connection, &upload.metadata.hash, &object_key, data)?;
+                let created = blob.object_key == object_key;
+                if created {
+                    for (name, variant) in &upload.variants {
+                        diesel::insert_into(variants::table)
+                            .values(&NewVariant {
+                                blob_id: blob.blob_id,
+                                name,
+                                format: variant.format() as i32,
+                                object_key: format!("{}.{}", object_key, name),
+                                data: Some(variant.data().to_vec()).filter(|_| !external)
+                            })
+                            .execute(&connection)?;
+                    }
+                }
+
+                let image_uploaded = UploadedImage::generate();
+                diesel::insert_into(images::table)
+                    .values(&upload.insertable(&image_uploaded, blob.blob_id))
+                    .execute(&connection)?;
+                uploaded.push((image_uploaded, created));
+            }
+
+            Ok(uploaded)
+        })
+    }
+
+    pub(super) fn load_row(&self, id: &ImageId) -> Result<ImageRow, Error> {
+        let connection = self.connection()?;
+        let (row, object_key, data) = images::table
+            .inner_join(blobs::table)
+            .filter(images::public_id.eq(id.as_str()))
+            .select((METADATA_COLUMNS, blobs::object_key, blobs::data))
+            .first::<(MetadataRow, String, Option<Vec<u8>>)>(&connection)
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?;
+
+        Ok(ImageRow {
+            metadata: row.into_metadata()?,
+            object_key,
+            data
+        })
+    }
+
+    pub(super) fn load_metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+        let connection = self.connection()?;
+        images::table
+            .filter(images::public_id.eq(id.as_str()))
+            .select(METADATA_COLUMNS)
+            .first::<MetadataRow>(&connection)
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?
+            .into_metadata()
+    }
+
+    /// None if image exists, but variant wasn't stored
+    pub(super) fn load_variant_row(&self, id: &ImageId, name: &str) -> Result<Option<VariantRow>, Error> {
+        let connection = self.connection()?;
+        let blob_id = images::table
+            .filter(images::public_id.eq(id.as_str()))
+            .select(images::blob_id)
+            .first::<i32>(&connection)
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?;
+
+        let row = variants::table.find((blob_id, name))
+            .select((variants::format, variants::object_key, variants::data))
+            .first::<(i32, String, Option<Vec<u8>>)>(&connection)
+            .optional()?;
+
+        match row {
+            Some((format, object_key, data)) => Ok(Some(VariantRow {
+                format: ImageFormat::from_i32(format)
+                    .ok_or_else(|| format_err!("Unknown image format: {}", format))?,
+                object_key,
+                data
+            })),
+            None => Ok(None)
+        }
+    }
+
+    /// blobs stored before deduplication have empty hash, they are never shared
+    pub(super) fn contains_blob(&self, hash: &str) -> Result<bool, Error> {
+        if hash.is_empty() {
+            return Ok(false);
+        }
+
+        let connection = self.connection()?;
+        let exists = diesel::select(diesel::dsl::exists(blobs::table.filter(blobs::hash.eq(hash))))
+            .get_result::<bool>(&connection)?;
+        Ok(exists)
+    }
+
+    /// Add variant to blob of image, its object key is "{key}.{name}" where key is object key of blob.
+    /// Variant which was already stored (e.g. by concurrent request) is kept, false is returned
+    /// if nothing was inserted.
+    pub(super) fn insert_variant(&self, id: &ImageId, name: &str, variant: &Image, external: bool) -> Result<bool, Error> {
+        let connection = self.connection()?;
+        let data = Some(variant.data()).filter(|_| !external);
+        let inserted = diesel::sql_query(
+            "INSERT INTO variants (blob_id, name, format, object_key, data) \
+             SELECT images.blob_id, $2, $3, blobs.object_key || '.' || $2, $4 \
+             FROM images INNER JOIN blobs ON blobs.blob_id = images.blob_id \
+             WHERE images.public_id = $1 \
+             ON CONFLICT DO NOTHING"
+        )
+            .bind::<Text, _>(id.as_str())
+            .bind::<Text, _>(name)
+            .bind::<Integer, _>(variant.format() as i32)
+            .bind::<Nullable<Binary>, _>(data)
+            .execute(&connection)?;
+        Ok(inserted > 0)
+    }
+
+    /// delete image, object keys of its blob and variants are returned if blob is not used anymore
+    pub(super) fn delete_row(&self, id: &ImageId, token: &str) -> Result<Vec<String>, Error> {
+        let connection = self.connection()?;
+        connection.transaction::<_, Error, _>(|| {
+            let (blob_id, stored_token) = images::table
+                .filter(images::public_id.eq(id.as_str()))
+                .select((images::blob_id, images::delete_token))
+                .for_update()
+                .first::<(i32, String)>(&connection)
+                .optional()?
+                .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?;
+
+            if !DeleteToken(stored_token).matches(token) {
+                return Err(ApiError::Forbidden("Invalid delete token".to_string()).into());
+            }
+
+            diesel::delete(images::table.filter(images::public_id.eq(id.as_str())))
+                .execute(&connection)?;
+
+            let refcount = diesel::update(blobs::table.find(blob_id))
+                .set(blobs::refcount.eq(blobs::refcount - 1))
+                .returning(blobs::refcount)
+                .get_result::<i32>(&connection)?;
+            if refcount > 0 {
+                return Ok(Vec::new());
+            }
+
+            let mut object_keys = diesel::delete(variants::table.filter(variants::blob_id.eq(blob_id)))
+                .returning(variants::object_key)
+                .get_results::<String>(&connection)?;
+            let object_key = diesel::delete(blobs::table.find(blob_id))
+                .returning(blobs::object_key)
+                .get_result::<String>(&connection)?;
+            object_keys.push(object_key);
+            Ok(object_keys)
+        })
+    }
+
+    /// database key and creation time of job
+    fn find_job(&self, connection: &PgConnection, id: &JobId) -> Result<(i32, DateTime<Utc>), Error> {
+        jobs::table
+            .filter(jobs::public_id.eq(id.as_str()))
+            .select((jobs::job_id, jobs::created_at))
+            .first::<(i32, DateTime<Utc>)>(connection)
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("No job with id={}", id)).into())
+    }
 }
 
 impl Storage for PostgresStorage {
-    fn store(&self, imgs: Vec<Image>) -> Result<Vec<ImageId>, Error> { 
-        use crate::schema::images::dsl::*;
-        
-        let connection = self.connections.get()?;
-        let imgs = imgs.into_iter()
-            .map(|image| image.insertable())
-            .collect::<Result<Vec<_>, Error>>()?;
-        
-        let ids: Vec<ImageId> = diesel::insert_into(images)
-            .values(&imgs)
-            .returning(image_id)
-            .get_results::<i32>(&connection)?
+    fn store(&self, images: Vec<Upload>) -> Result<Vec<UploadedImage>, Error> {
+        let uploaded: Vec<UploadedImage> = self.insert(&images, None)?
             .into_iter()
-            .map(ImageId)
+            .map(|(uploaded, _)| uploaded)
             .collect();
-       
-        let total_size: usize = imgs.iter().map(|img| img.data.len()).sum();
-        info!("Successfully stored {} images (total size is {}): {:?}", 
+
+        let ids: Vec<&str> = uploaded.iter().map(|uploaded| uploaded.id.as_str()).collect();
+        let total_size: u64 = images.iter().map(|image| image.metadata.size).sum();
+        info!("Successfully stored {} images (total size is {}): {:?}",
               ids.len(), total_size, ids);
-        Ok(ids)
+        Ok(uploaded)
+    }
+
+    fn load(&self, id: &ImageId) -> Result<Image, Error> {
+        let row = self.load_row(id)?;
+        let data = row.data
+            .ok_or_else(|| format_err!("Data of image with id={} is not stored in database", id))?;
+
+        Ok(Image::from_raw(data, row.metadata.format))
+    }
+
+    fn read_range(&self, id: &ImageId, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+        // only requested part of blob is sent by database (by single query, so it is kept in memory),
+        // bounds are numbers, so they can be inlined
+        let part = sql::<Nullable<Binary>>(&format!(
+            "substring(blobs.data from {} for {})", range.start + 1, range.end - range.start
+        ));
+
+        let connection = self.connection()?;
+        let data = images::table
+            .inner_join(blobs::table)
+            .filter(images::public_id.eq(id.as_str()))
+            .select(part)
+            .first::<Option<Vec<u8>>>(&connection)
+            .optional()?
+            .ok_or_else(|| ApiError::NotFound(format!("No image with id={}", id)))?
+            .ok_or_else(|| format_err!("Data of image with id={} is not stored in database", id))?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+        self.load_metadata(id)
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, Error> {
+        self.contains_blob(hash)
+    }
+
+    fn load_variant(&self, id: &ImageId, name: &str) -> Result<Option<Image>, Error> {
+        let row = match self.load_variant_row(id, name)? {
+            Some(row) => row,
+            None => return Ok(None)
+        };
+        let data = row.data
+            .ok_or_else(|| format_err!("Data of variant {} of image with id={} is not stored in database", name, id))?;
+
+        Ok(Some(Image::from_raw(data, row.format)))
+    }
+
+    fn store_variant(&self, id: &ImageId, name: &str, variant: &Image) -> Result<(), Error> {
+        self.insert_variant(id, name, variant, false)?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &ImageId, token: &str) -> Result<(), Error> {
+        self.delete_row(id, token)?;
+        info!("Deleted image with id={}", id);
+        Ok(())
+    }
+
+    fn create_job(&self, job: &Job) -> Result<(), Error> {
+        let connection = self.connection()?;
+        connection.transaction::<_, Error, _>(|| {
+            let job_id = diesel::insert_into(jobs::table)
+                .values(&NewJob { public_id: job.id.as_str(), created_at: job.created_at })
+                .returning(jobs::job_id)
+                .get_result::<i32>(&connection)?;
+
+            let items: Vec<JobItemRow> = job.items.iter()
+                .enumerate()
+                .map(|(position, item)| JobItemRow::new(job_id, position, item))
+                .collect();
+            diesel::insert_into(job_items::table)
+                .values(&items)
+                .execute(&connection)?;
+            Ok(())
+        })
     }
 
-    fn load(&self, id: ImageId) -> Result<Image, Error> {
-        use crate::schema::images::dsl::*;
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error> {
+        let connection = self.connection()?;
+        let (job_id, _) = self.find_job(&connection, id)?;
+        let row = JobItemRow::new(job_id, position, item);
 
-        let connection = self.connections.get()?;
-        let image = images.filter(image_id.eq(id.inner()))
-            .load::<LoadedImage>(&connection)?;
+        diesel::update(job_items::table.find((job_id, row.position)))
+            .set((
+                job_items::status.eq(row.status),
+                job_items::image_id.eq(row.image_id),
+                job_items::delete_token.eq(row.delete_token),
+                job_items::error_code.eq(row.error_code),
+                job_items::error_message.eq(row.error_message)
+            ))
+            .execute(&connection)?;
+        Ok(())
+    }
 
-        let image = image.into_iter()
-            .next()
-            .ok_or_else(|| format_err!("No image with id={}", id.inner()))?;
-        
-        let f = ImageFormat::from_i32(image.format)
-            .ok_or_else(|| format_err!("Unknown image format: {}", image.format))?;
+    fn interrupt_jobs(&self) -> Result<usize, Error> {
+        let connection = self.connection()?;
+        let row = JobItemRow::new(0, 0, &JobItem::interrupted(String::new()));
+        let unfinished = [JobStatus::Queued.as_str(), JobStatus::Running.as_str()];
 
-        Image::decode(&image.data, f)
+        let count = diesel::update(job_items::table.filter(job_items::status.eq_any(&unfinished[..])))
+            .set((
+                job_items::status.eq(row.status),
+                job_items::error_code.eq(row.error_code),
+                job_items::error_message.eq(row.error_message)
+            ))
+            .execute(&connection)?;
+        Ok(count)
+    }
+
+    fn load_job(&self, id: &JobId) -> Result<Job, Error> {
+        let connection = self.connection()?;
+        let (job_id, created_at) = self.find_job(&connection, id)?;
+        let items = job_items::table
+            .filter(job_items::job_id.eq(job_id))
+            .order(job_items::position)
+            .load::<JobItemRow>(&connection)?
+            .into_iter()
+            .map(JobItemRow::into_item)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Job {
+            id: id.clone(),
+            created_at,
+            items
+        })
     }
 }
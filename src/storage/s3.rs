@@ -0,0 +1,664 @@
+use std::io::Read;
+use std::ops::Range;
+
+use log::{info, warn};
+use futures::{Future, Stream};
+use failure::{Error, format_err};
+use rusoto_core::{Region, HttpClient, RusotoError};
+use rusoto_core::credential::{StaticProvider, DefaultCredentialsProvider};
+use rusoto_s3::{
+    S3, S3Client,
+    PutObjectRequest, GetObjectRequest, GetObjectError, DeleteObjectRequest, StreamingBody
+};
+
+use crate::error::ApiError;
+use crate::models::{ImageId, Image, ImageMetadata, Upload, UploadedImage, Job, JobId, JobItem};
+use super::{Storage, PostgresStorage};
+use super::postgres::{ImageRow, VariantRow};
+
+/// objects with image data, names are relative to bucket prefix
+pub(crate) trait Bucket: 'static + Send + Sync {
+    fn put(&self, name: &str, image: &Image) -> Result<(), Error>;
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error>;
+    fn read_range(&self, name: &str, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error>;
+    fn delete(&self, name: &str) -> Result<(), Error>;
+}
+
+/// Metadata of images, which refer to objects in bucket.
+/// Implemented by postgres storage, see its methods with the same names
+pub(crate) trait BlobIndex: 'static + Send + Sync {
+    fn insert(&self, uploads: &[Upload], object_keys: Option<&[String]>) -> Result<Vec<(UploadedImage, bool)>, Error>;
+    fn load_row(&self, id: &ImageId) -> Result<ImageRow, Error>;
+    fn load_metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error>;
+    fn load_variant_row(&self, id: &ImageId, name: &str) -> Result<Option<VariantRow>, Error>;
+    fn contains_blob(&self, hash: &str) -> Result<bool, Error>;
+    fn insert_variant(&self, id: &ImageId, name: &str, variant: &Image, external: bool) -> Result<bool, Error>;
+    fn delete_row(&self, id: &ImageId, token: &str) -> Result<Vec<String>, Error>;
+
+    fn create_job(&self, job: &Job) -> Result<(), Error>;
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error>;
+    fn load_job(&self, id: &JobId) -> Result<Job, Error>;
+    fn interrupt_jobs(&self) -> Result<usize, Error>;
+}
+
+impl BlobIndex for PostgresStorage {
+    fn insert(&self, uploads: &[Upload], object_keys: Option<&[String]>) -> Result<Vec<(UploadedImage, bool)>, Error> {
+        PostgresStorage::insert(self, uploads, object_keys)
+    }
+
+    fn load_row(&self, id: &ImageId) -> Result<ImageRow, Error> {
+        PostgresStorage::load_row(self, id)
+    }
+
+    fn load_metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+        PostgresStorage::load_metadata(self, id)
+    }
+
+    fn load_variant_row(&self, id: &ImageId, name: &str) -> Result<Option<VariantRow>, Error> {
+        PostgresStorage::load_variant_row(self, id, name)
+    }
+
+    fn contains_blob(&self, hash: &str) -> Result<bool, Error> {
+        PostgresStorage::contains_blob(self, hash)
+    }
+
+    fn insert_variant(&self, id: &ImageId, name: &str, variant: &Image, external: bool) -> Result<bool, Error> {
+        PostgresStorage::insert_variant(self, id, name, variant, external)
+    }
+
+    fn delete_row(&self, id: &ImageId, token: &str) -> Result<Vec<String>, Error> {
+        PostgresStorage::delete_row(self, id, token)
+    }
+
+    fn create_job(&self, job: &Job) -> Result<(), Error> {
+        Storage::create_job(self, job)
+    }
+
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error> {
+        Storage::update_job_item(self, id, position, item)
+    }
+
+    fn load_job(&self, id: &JobId) -> Result<Job, Error> {
+        Storage::load_job(self, id)
+    }
+
+    fn interrupt_jobs(&self) -> Result<usize, Error> {
+        Storage::interrupt_jobs(self)
+    }
+}
+
+/// S3 compatible bucket with image data
+pub struct S3Bucket {
+    client: S3Client,
+    bucket: String,
+    prefix: String
+}
+
+impl S3Bucket {
+    /// `endpoint` is used for S3 compatible services (e.g. MinIO),
+    /// if credentials are not set, default provider chain is used
+    pub fn new(
+        bucket: &str,
+        prefix: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        credentials: Option<(String, String)>
+    ) -> Result<Self, Error> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                name: region.to_string(),
+                endpoint: endpoint.to_string()
+            },
+            None => region.parse()?
+        };
+
+        let dispatcher = HttpClient::new()?;
+        let client = match credentials {
+            Some((access_key, secret_key)) => {
+                let provider = StaticProvider::new_minimal(access_key, secret_key);
+                S3Client::new_with(dispatcher, provider, region)
+            },
+            None => S3Client::new_with(dispatcher, DefaultCredentialsProvider::new()?, region)
+        };
+
+        Ok(S3Bucket {
+            client,
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string()
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    fn unavailable<E: std::error::Error>(e: E) -> Error {
+        ApiError::StorageUnavailable(format!("S3 request failed: {}", e)).into()
+    }
+
+    fn get_object(&self, name: &str, range: Option<String>) -> Result<StreamingBody, Error> {
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(name),
+            range,
+            ..GetObjectRequest::default()
+        };
+
+        let output = self.client.get_object(request)
+            .sync()
+            .map_err(|e| match e {
+                RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+                    ApiError::NotFound(format!("No object {}", name)).into()
+                },
+                e => S3Bucket::unavailable(e)
+            })?;
+
+        output.body
+            .ok_or_else(|| format_err!("Empty response for object {}", name))
+    }
+}
+
+impl Bucket for S3Bucket {
+    fn put(&self, name: &str, image: &Image) -> Result<(), Error> {
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(name),
+            body: Some(image.data().to_vec().into()),
+            content_type: Some(image.format().content_type()),
+            content_length: Some(image.data().len() as i64),
+            ..PutObjectRequest::default()
+        };
+
+        self.client.put_object(request)
+            .sync()
+            .map_err(S3Bucket::unavailable)?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let body = self.get_object(name, None)?;
+        let data = body.concat2().wait().map_err(S3Bucket::unavailable)?;
+        Ok(data.to_vec())
+    }
+
+    /// only given part of object is downloaded, body is read as it is received
+    fn read_range(&self, name: &str, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+        // range of header is inclusive
+        let body = self.get_object(name, Some(format!("bytes={}-{}", range.start, range.end - 1)))?;
+        Ok(Box::new(body.into_blocking_read()))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.key(name),
+            ..DeleteObjectRequest::default()
+        };
+
+        self.client.delete_object(request)
+            .sync()
+            .map_err(S3Bucket::unavailable)?;
+        Ok(())
+    }
+}
+
+/// names of objects of upload, variants are stored next to image
+fn object_names<'a>(key: &str, upload: &'a Upload) -> Vec<(String, &'a Image)> {
+    std::iter::once((key.to_string(), &upload.image))
+        .chain(upload.variants.iter().map(|(name, variant)| (format!("{}.{}", key, name), variant)))
+        .collect()
+}
+
+/// Keeps image data in S3 compatible bucket, while metadata is stored in postgres
+pub struct S3Storage<M = PostgresStorage, B = S3Bucket> {
+    metadata: M,
+    bucket: B
+}
+
+impl<M: BlobIndex, B: Bucket> S3Storage<M, B> {
+    pub fn new(metadata: M, bucket: B) -> Self {
+        S3Storage {
+            metadata,
+            bucket
+        }
+    }
+
+    /// remove objects which are not referenced from database
+    fn cleanup(&self, objects: &[String]) {
+        for name in objects {
+            if let Err(e) = self.bucket.delete(name) {
+                warn!("Failed to remove orphaned object {}: {}", name, e);
+            }
+        }
+    }
+}
+
+impl<M: BlobIndex, B: Bucket> Storage for S3Storage<M, B> {
+    fn store(&self, images: Vec<Upload>) -> Result<Vec<UploadedImage>, Error> {
+        // objects are uploaded before transaction, so metadata never points to missing object
+        // and database locks are not held during uploads
+        let keys: Vec<String> = images.iter().map(|_| ImageId::generate().0).collect();
+        let objects: Vec<Vec<(String, &Image)>> = images.iter()
+            .zip(&keys)
+            .map(|(upload, key)| object_names(key, upload))
+            .collect();
+
+        let mut created = Vec::new();
+        let result = objects.iter().flatten().try_for_each(|(name, image)| -> Result<(), Error> {
+            self.bucket.put(name, image)?;
+            created.push(name.clone());
+            Ok(())
+        });
+
+        let inserted = match result.and_then(|_| self.metadata.insert(&images, Some(&keys))) {
+            Ok(inserted) => inserted,
+            Err(e) => {
+                self.cleanup(&created);
+                return Err(e);
+            }
+        };
+
+        // blobs with the same hash already existed, so objects of these uploads are not referenced
+        let unused: Vec<String> = inserted.iter()
+            .zip(&objects)
+            .filter(|((_, new_blob), _)| !new_blob)
+            .flat_map(|(_, objects)| objects.iter().map(|(name, _)| name.clone()))
+            .collect();
+        self.cleanup(&unused);
+
+        let total_size: u64 = images.iter().map(|image| image.metadata.size).sum();
+        info!("Successfully stored {} images in S3 ({} new objects, total size is {})",
+              inserted.len(), created.len() - unused.len(), total_size);
+        Ok(inserted.into_iter().map(|(uploaded, _)| uploaded).collect())
+    }
+
+    fn load(&self, id: &ImageId) -> Result<Image, Error> {
+        let row = self.metadata.load_row(id)?;
+        let data = self.bucket.get(&row.object_key)?;
+        Ok(Image::from_raw(data, row.metadata.format))
+    }
+
+    fn read_range(&self, id: &ImageId, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+        let row = self.metadata.load_row(id)?;
+        self.bucket.read_range(&row.object_key, range)
+    }
+
+    fn metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+        self.metadata.load_metadata(id)
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, Error> {
+        self.metadata.contains_blob(hash)
+    }
+
+    fn load_variant(&self, id: &ImageId, name: &str) -> Result<Option<Image>, Error> {
+        match self.metadata.load_variant_row(id, name)? {
+            Some(row) => {
+                let data = self.bucket.get(&row.object_key)?;
+                Ok(Some(Image::from_raw(data, row.format)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn store_variant(&self, id: &ImageId, name: &str, variant: &Image) -> Result<(), Error> {
+        // object name is given by blob, so concurrent requests write the same object;
+        // it stays orphaned only if image is deleted before variant is inserted
+        let row = self.metadata.load_row(id)?;
+        self.bucket.put(&format!("{}.{}", row.object_key, name), variant)?;
+        self.metadata.insert_variant(id, name, variant, true)?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &ImageId, token: &str) -> Result<(), Error> {
+        // objects are removed only when no other image shares them,
+        // image is already unreachable, so failure to delete object is not fatal
+        for name in self.metadata.delete_row(id, token)? {
+            if let Err(e) = self.bucket.delete(&name) {
+                warn!("Failed to remove object {}: {}", name, e);
+            }
+        }
+
+        info!("Deleted image with id={}", id);
+        Ok(())
+    }
+
+    fn create_job(&self, job: &Job) -> Result<(), Error> {
+        self.metadata.create_job(job)
+    }
+
+    fn update_job_item(&self, id: &JobId, position: usize, item: &JobItem) -> Result<(), Error> {
+        self.metadata.update_job_item(id, position, item)
+    }
+
+    fn load_job(&self, id: &JobId) -> Result<Job, Error> {
+        self.metadata.load_job(id)
+    }
+
+    fn interrupt_jobs(&self) -> Result<usize, Error> {
+        self.metadata.interrupt_jobs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{Cursor, Read};
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+
+    use failure::{Error, bail, format_err};
+
+    use super::{S3Bucket, S3Storage, Bucket, BlobIndex};
+    use crate::storage::Storage;
+    use crate::storage::postgres::{ImageRow, VariantRow};
+    use crate::models::{
+        Upload, Image, ImageFormat, ImageId, ImageMetadata, ImageSource, UploadedImage, DeleteToken, Job, JobId, JobItem
+    };
+
+    const TEST_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/test-data/servo.png"));
+
+    /// requests to bucket and index, in order they were made
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    #[derive(Default)]
+    struct FakeBucket {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        log: Log,
+        /// number of put request which fails
+        failing: Option<usize>
+    }
+
+    impl FakeBucket {
+        fn names(&self) -> Vec<String> {
+            let mut names: Vec<String> = self.objects.lock().unwrap().keys().cloned().collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Bucket for FakeBucket {
+        fn put(&self, name: &str, image: &Image) -> Result<(), Error> {
+            let mut log = self.log.lock().unwrap();
+            if self.failing == Some(log.iter().filter(|entry| entry.starts_with("put ")).count()) {
+                bail!("Put of {} failed", name);
+            }
+            log.push(format!("put {}", name));
+            self.objects.lock().unwrap().insert(name.to_string(), image.data().to_vec());
+            Ok(())
+        }
+
+        fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+            self.objects.lock().unwrap().get(name).cloned().ok_or_else(|| format_err!("No object {}", name))
+        }
+
+        fn read_range(&self, name: &str, range: Range<u64>) -> Result<Box<dyn Read + Send>, Error> {
+            let data = self.get(name)?;
+            Ok(Box::new(Cursor::new(data[range.start as usize..range.end as usize].to_vec())))
+        }
+
+        fn delete(&self, name: &str) -> Result<(), Error> {
+            self.log.lock().unwrap().push(format!("delete {}", name));
+            self.objects.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    struct FakeBlob {
+        object_key: String,
+        refcount: usize,
+        /// name, format and object key
+        variants: Vec<(String, ImageFormat, String)>
+    }
+
+    /// keeps blobs like postgres storage, blobs are shared by hash
+    #[derive(Default)]
+    struct FakeIndex {
+        blobs: Mutex<HashMap<String, FakeBlob>>,
+        /// metadata, hash and delete token
+        images: Mutex<HashMap<ImageId, (ImageMetadata, String, DeleteToken)>>,
+        log: Log,
+        failing: bool
+    }
+
+    impl FakeIndex {
+        fn image(&self, id: &ImageId) -> Result<(ImageMetadata, String, DeleteToken), Error> {
+            self.images.lock().unwrap().get(id).cloned().ok_or_else(|| format_err!("No image with id={}", id))
+        }
+    }
+
+    impl BlobIndex for FakeIndex {
+        fn insert(&self, uploads: &[Upload], object_keys: Option<&[String]>) -> Result<Vec<(UploadedImage, bool)>, Error> {
+            self.log.lock().unwrap().push("insert".to_string());
+            if self.failing {
+                bail!("Insert failed");
+            }
+
+            let keys = object_keys.expect("S3 storage has to pass object keys");
+            let mut blobs = self.blobs.lock().unwrap();
+            let mut images = self.images.lock().unwrap();
+            Ok(uploads.iter().zip(keys).map(|(upload, key)| {
+                let hash = upload.metadata.hash.clone();
+                let created = !blobs.contains_key(&hash);
+                let blob = blobs.entry(hash.clone()).or_insert_with(|| FakeBlob {
+                    object_key: key.clone(),
+                    refcount: 0,
+                    variants: upload.variants.iter()
+                        .map(|(name, variant)| (name.clone(), variant.format(), format!("{}.{}", key, name)))
+                        .collect()
+                });
+                blob.refcount += 1;
+
+                let uploaded = UploadedImage::generate();
+                images.insert(uploaded.id.clone(), (upload.metadata.clone(), hash, uploaded.delete_token.clone()));
+                (uploaded, created)
+            }).collect())
+        }
+
+        fn load_row(&self, id: &ImageId) -> Result<ImageRow, Error> {
+            let (metadata, hash, _) = self.image(id)?;
+            let object_key = self.blobs.lock().unwrap()[&hash].object_key.clone();
+            Ok(ImageRow { metadata, object_key, data: None })
+        }
+
+        fn load_metadata(&self, id: &ImageId) -> Result<ImageMetadata, Error> {
+            Ok(self.image(id)?.0)
+        }
+
+        fn load_variant_row(&self, id: &ImageId, name: &str) -> Result<Option<VariantRow>, Error> {
+            let (_, hash, _) = self.image(id)?;
+            Ok(self.blobs.lock().unwrap()[&hash].variants.iter()
+                .find(|(variant, _, _)| variant == name)
+                .map(|(_, format, object_key)| VariantRow { format: *format, object_key: object_key.clone(), data: None }))
+        }
+
+        fn contains_blob(&self, hash: &str) -> Result<bool, Error> {
+            Ok(self.blobs.lock().unwrap().contains_key(hash))
+        }
+
+        fn insert_variant(&self, id: &ImageId, name: &str, variant: &Image, external: bool) -> Result<bool, Error> {
+            assert!(external);
+            self.log.lock().unwrap().push(format!("insert variant {}", name));
+            let (_, hash, _) = self.image(id)?;
+            let mut blobs = self.blobs.lock().unwrap();
+            let blob = blobs.get_mut(&hash).unwrap();
+            if blob.variants.iter().any(|(variant, _, _)| variant == name) {
+                return Ok(false);
+            }
+            let object_key = format!("{}.{}", blob.object_key, name);
+            blob.variants.push((name.to_string(), variant.format(), object_key));
+            Ok(true)
+        }
+
+        fn delete_row(&self, id: &ImageId, token: &str) -> Result<Vec<String>, Error> {
+            let (_, hash, delete_token) = self.image(id)?;
+            if !delete_token.matches(token) {
+                bail!("Invalid delete token");
+            }
+            self.images.lock().unwrap().remove(id);
+
+            let mut blobs = self.blobs.lock().unwrap();
+            let blob = blobs.get_mut(&hash).unwrap();
+            blob.refcount -= 1;
+            if blob.refcount > 0 {
+                return Ok(Vec::new());
+            }
+            let blob = blobs.remove(&hash).unwrap();
+            Ok(blob.variants.into_iter()
+                .map(|(_, _, object_key)| object_key)
+                .chain(std::iter::once(blob.object_key))
+                .collect())
+        }
+
+        fn create_job(&self, _: &Job) -> Result<(), Error> {
+            unreachable!("jobs are not used by these tests")
+        }
+
+        fn update_job_item(&self, _: &JobId, _: usize, _: &JobItem) -> Result<(), Error> {
+            unreachable!("jobs are not used by these tests")
+        }
+
+        fn load_job(&self, _: &JobId) -> Result<Job, Error> {
+            unreachable!("jobs are not used by these tests")
+        }
+
+        fn interrupt_jobs(&self) -> Result<usize, Error> {
+            unreachable!("jobs are not used by these tests")
+        }
+    }
+
+    fn fake_storage(failing_put: Option<usize>, failing_insert: bool) -> (S3Storage<FakeIndex, FakeBucket>, Log) {
+        let log = Log::default();
+        let index = FakeIndex { log: log.clone(), failing: failing_insert, ..FakeIndex::default() };
+        let bucket = FakeBucket { log: log.clone(), failing: failing_put, ..FakeBucket::default() };
+        (S3Storage::new(index, bucket), log)
+    }
+
+    fn test_upload() -> Upload {
+        Upload::decode(TEST_IMAGE, ImageFormat::PNG, ImageSource::Multipart).unwrap()
+            .with_variants(&["thumb=16".parse().unwrap()])
+            .unwrap()
+    }
+
+    /// object key of image, variants are named after it
+    fn object_key(storage: &S3Storage<FakeIndex, FakeBucket>, id: &ImageId) -> String {
+        storage.metadata.load_row(id).unwrap().object_key
+    }
+
+    #[test]
+    fn store_uploads_before_insert() {
+        let (storage, log) = fake_storage(None, false);
+        let upload = test_upload();
+        let uploaded = storage.store(vec![upload.clone()]).unwrap().remove(0);
+
+        let key = object_key(&storage, &uploaded.id);
+        assert_eq!(*log.lock().unwrap(), vec![format!("put {}", key), format!("put {}.thumb", key), "insert".to_string()]);
+        assert_eq!(storage.bucket.names(), vec![key.clone(), format!("{}.thumb", key)]);
+
+        assert_eq!(storage.load(&uploaded.id).unwrap().data(), TEST_IMAGE);
+        let mut part = Vec::new();
+        storage.read_range(&uploaded.id, 10..20).unwrap().read_to_end(&mut part).unwrap();
+        assert_eq!(&part[..], &TEST_IMAGE[10..20]);
+        let thumb = storage.load_variant(&uploaded.id, "thumb").unwrap().unwrap();
+        assert_eq!(thumb.data(), upload.variants[0].1.data());
+        assert!(storage.load_variant(&uploaded.id, "small").unwrap().is_none());
+    }
+
+    #[test]
+    fn store_removes_objects_of_duplicates() {
+        let (storage, log) = fake_storage(None, false);
+        let first = storage.store(vec![test_upload()]).unwrap().remove(0);
+        let key = object_key(&storage, &first.id);
+        log.lock().unwrap().clear();
+
+        // identical uploads in one request share blob of the first one too
+        let duplicates = storage.store(vec![test_upload(), test_upload()]).unwrap();
+        for uploaded in &duplicates {
+            assert_eq!(object_key(&storage, &uploaded.id), key);
+        }
+        assert_eq!(storage.bucket.names(), vec![key.clone(), format!("{}.thumb", key)]);
+
+        let log = log.lock().unwrap();
+        let insert = log.iter().position(|entry| entry == "insert").unwrap();
+        assert_eq!(log[..insert].iter().filter(|entry| entry.starts_with("put ")).count(), 4);
+        assert_eq!(log[insert + 1..].iter().filter(|entry| entry.starts_with("delete ")).count(), 4);
+        assert!(storage.contains(&test_upload().metadata.hash).unwrap());
+    }
+
+    #[test]
+    fn store_removes_objects_after_failure() {
+        let (storage, log) = fake_storage(None, true);
+        assert!(storage.store(vec![test_upload()]).is_err());
+        assert!(storage.bucket.names().is_empty());
+        assert_eq!(log.lock().unwrap().iter().filter(|entry| entry.starts_with("delete ")).count(), 2);
+
+        // objects which were uploaded before failed one are removed, nothing is inserted
+        let (storage, log) = fake_storage(Some(1), false);
+        assert!(storage.store(vec![test_upload()]).is_err());
+        assert!(storage.bucket.names().is_empty());
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2);
+        assert!(log[0].starts_with("put ") && log[1] == log[0].replacen("put", "delete", 1));
+    }
+
+    #[test]
+    fn store_variant_naming() {
+        let (storage, log) = fake_storage(None, false);
+        let uploaded = storage.store(vec![test_upload()]).unwrap().remove(0);
+        let key = object_key(&storage, &uploaded.id);
+        log.lock().unwrap().clear();
+
+        let variant = storage.load_variant(&uploaded.id, "thumb").unwrap().unwrap();
+        storage.store_variant(&uploaded.id, "small", &variant).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![format!("put {}.small", key), "insert variant small".to_string()]);
+        assert_eq!(storage.load_variant(&uploaded.id, "small").unwrap().unwrap().data(), variant.data());
+        assert_eq!(storage.metadata.load_variant_row(&uploaded.id, "small").unwrap().unwrap().object_key, format!("{}.small", key));
+    }
+
+    #[test]
+    fn delete_removes_unused_objects() {
+        let (storage, log) = fake_storage(None, false);
+        let uploaded = storage.store(vec![test_upload(), test_upload()]).unwrap();
+        let key = object_key(&storage, &uploaded[0].id);
+        let variant = storage.load_variant(&uploaded[0].id, "thumb").unwrap().unwrap();
+        storage.store_variant(&uploaded[0].id, "small", &variant).unwrap();
+
+        assert!(storage.delete(&uploaded[0].id, "wrong").is_err());
+        storage.delete(&uploaded[0].id, uploaded[0].delete_token.as_str()).unwrap();
+        assert_eq!(storage.bucket.names().len(), 3);
+
+        log.lock().unwrap().clear();
+        storage.delete(&uploaded[1].id, uploaded[1].delete_token.as_str()).unwrap();
+        assert!(storage.bucket.names().is_empty());
+        let mut deleted = log.lock().unwrap().clone();
+        deleted.sort();
+        assert_eq!(deleted, vec![format!("delete {}", key), format!("delete {}.small", key), format!("delete {}.thumb", key)]);
+    }
+
+    #[test]
+    fn bucket_put_get() {
+        let name = ImageId::generate().0;
+        let path = format!("/test-bucket/images/{}", name);
+        let bucket = S3Bucket::new(
+            "test-bucket",
+            "images/",
+            "local",
+            Some(&mockito::server_url()),
+            Some(("access".to_string(), "secret".to_string()))
+        ).unwrap();
+
+        let _put = mockito::mock("PUT", path.as_str())
+            .with_status(200)
+            .create();
+        let _get = mockito::mock("GET", path.as_str())
+            .with_status(200)
+            .with_header("Content-Type", "image/png")
+            .with_body(TEST_IMAGE)
+            .create();
+
+        let upload = Upload::decode(TEST_IMAGE, ImageFormat::PNG, ImageSource::Multipart).unwrap();
+        bucket.put(&name, &upload.image).unwrap();
+        assert_eq!(bucket.get(&name).unwrap(), TEST_IMAGE);
+        assert!(bucket.get("missing").is_err());
+    }
+}